@@ -0,0 +1,81 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use super::{align_up, HeapBackend, HeapUsage, Locked};
+
+// バンプアロケータ
+// 次に割り当てるアドレスを前に進めていくだけなので速いが、
+// 割り当てたものが全部解放されるまでメモリを再利用できない
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    // 解放されていない割り当ての数
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapBackend for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
+
+    // 解放されていても next より前は使えないので、使用中として数える
+    fn usage(&self) -> HeapUsage {
+        HeapUsage {
+            used: self.next - self.heap_start,
+            free: self.heap_end - self.next,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut bump = self.lock();
+
+        let alloc_start = align_up(bump.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return null_mut(),
+        };
+
+        if alloc_end > bump.heap_end {
+            null_mut()
+        } else {
+            bump.next = alloc_end;
+            bump.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        let mut bump = self.lock();
+
+        // 全部解放されたら先頭から使い直せる
+        bump.allocations -= 1;
+        if bump.allocations == 0 {
+            bump.next = bump.heap_start;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::{self, NonNull}};
+use linked_list_allocator::Heap;
+use super::{HeapBackend, HeapUsage, Locked};
+
+// 決まった大きさのブロックごとに空きリストを持つアロケータ
+// 要求されたサイズ以上で一番小さいブロックを使うので、割り当ても解放も O(1) で済む
+// ブロックに収まらない大きな割り当てと、空きリストが空のときは linked_list_allocator に任せる
+
+// ブロックのサイズ(アラインメントも同じ値にする)
+// ノードを書き込むため 8 バイトより小さくはできない
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: Heap,
+    // 空きリストにつながっているブロックの合計バイト数
+    // フォールバック側から見ると使用中なので、使用量を出すときに差し引く
+    free_block_bytes: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: Heap::empty(),
+            free_block_bytes: 0,
+        }
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    fn usage(&self) -> HeapUsage {
+        HeapUsage {
+            used: self.fallback_allocator.used() - self.free_block_bytes,
+            free: self.fallback_allocator.free() + self.free_block_bytes,
+        }
+    }
+}
+
+// layout が収まるブロックのサイズの番号
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => match allocator.list_heads[index].take() {
+                Some(node) => {
+                    allocator.list_heads[index] = node.next.take();
+                    allocator.free_block_bytes -= BLOCK_SIZES[index];
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // 空きブロックがないので、フォールバックから新しく切り出す
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    allocator.fallback_alloc(layout)
+                }
+            },
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                // フォールバックには返さず、空きリストにつないで再利用する
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.free_block_bytes += BLOCK_SIZES[index];
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::Locked;
+
+// heap_leak_tracking フィーチャを有効にしたときだけ使う、解放されていない割り当ての記録
+// 割り当てごとに呼び出し元の戻りアドレスを覚えておき、リークしたときにどこで確保したかを表示する
+// 記録自体にヒープは使えないので固定長の表に置き、あふれた分は数だけ数える
+//
+// 戻りアドレスは backtrace::walk でフレームポインタ(rbp)をたどって集める
+
+// 同時に覚えておける割り当ての数
+const MAX_TRACKED: usize = 512;
+// 覚えておく戻りアドレスの数(アロケータ自身のフレームも含む)
+pub const CALL_SITE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    pub ptr: usize,
+    pub size: usize,
+    // 何番目の割り当てか(mark で返す値と比べる)
+    pub sequence: u64,
+    // 0 の要素はたどれなかったところ
+    pub call_site: [usize; CALL_SITE_DEPTH],
+}
+
+struct Tracker {
+    entries: [Option<LiveAllocation>; MAX_TRACKED],
+    // 表に入りきらなかった割り当ての数
+    dropped: usize,
+}
+
+static TRACKER: Locked<Tracker> = Locked::new(Tracker {
+    entries: [None; MAX_TRACKED],
+    dropped: 0,
+});
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// これ以降に確保された割り当てを leaks_since で調べられる
+pub fn mark() -> u64 {
+    SEQUENCE.load(Ordering::Relaxed)
+}
+
+// mark の後に確保され、まだ解放されていない割り当ての数
+pub fn leaks_since(mark: u64) -> usize {
+    let tracker = TRACKER.lock();
+    tracker.entries.iter()
+        .flatten()
+        .filter(|entry| entry.sequence >= mark)
+        .count()
+}
+
+// 表に入りきらず、記録できなかった割り当ての数
+pub fn dropped() -> usize {
+    TRACKER.lock().dropped
+}
+
+// mark の後に確保され、まだ解放されていない割り当てをシリアルに出力する
+pub fn report_leaks_since(mark: u64) {
+    for index in 0..MAX_TRACKED {
+        // 出力している間はロックを離しておく
+        let entry = match TRACKER.lock().entries[index] {
+            Some(entry) if entry.sequence >= mark => entry,
+            _ => continue,
+        };
+        crate::serial_print!("leak: {} bytes at {:#x}, allocated from", entry.size, entry.ptr);
+        for addr in entry.call_site.iter().take_while(|&&addr| addr != 0) {
+            crate::serial_print!(" {:#x}", addr);
+        }
+        crate::serial_println!();
+    }
+}
+
+pub(super) fn track(ptr: *mut u8, layout: Layout) {
+    let entry = LiveAllocation {
+        ptr: ptr as usize,
+        size: layout.size(),
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        call_site: call_site(),
+    };
+    let mut tracker = TRACKER.lock();
+    match tracker.entries.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(entry),
+        None => tracker.dropped += 1,
+    }
+}
+
+pub(super) fn untrack(ptr: *mut u8) {
+    let mut tracker = TRACKER.lock();
+    if let Some(slot) = tracker.entries.iter_mut()
+        .find(|slot| matches!(slot, Some(entry) if entry.ptr == ptr as usize))
+    {
+        *slot = None;
+    }
+}
+
+// rbp の連鎖をたどって戻りアドレスを集める
+#[inline(never)]
+fn call_site() -> [usize; CALL_SITE_DEPTH] {
+    let mut call_site = [0; CALL_SITE_DEPTH];
+    let mut depth = 0;
+    crate::backtrace::walk(crate::backtrace::frame_pointer(), |return_address| {
+        call_site[depth] = return_address as usize;
+        depth += 1;
+        depth < CALL_SITE_DEPTH
+    });
+    call_site
+}
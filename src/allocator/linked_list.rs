@@ -0,0 +1,158 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::null_mut};
+use super::{align_up, HeapBackend, HeapUsage, Locked};
+
+// 空き領域を単方向リストでつなぐアロケータ
+// リストのノードは空き領域そのものの先頭に書く
+// 隣り合った空き領域をまとめないので、使っているうちに断片化していく
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+pub struct LinkedListAllocator {
+    // 先頭のダミーノード(size は 0)
+    head: ListNode,
+    heap_start: usize,
+    heap_size: usize,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+            heap_start: 0,
+            heap_size: 0,
+        }
+    }
+
+    // 空き領域をリストの先頭に追加する
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // 空き領域にはノードが書き込めないといけない
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let mut node = ListNode::new(size);
+        node.next = self.head.next.take();
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        self.head.next = Some(&mut *node_ptr);
+    }
+
+    // 指定したサイズとアラインメントで割り当てられる空き領域を探し、リストから外して返す
+    fn find_region(&mut self, size: usize, align: usize)
+        -> Option<(&'static mut ListNode, usize)>
+    {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    // region から割り当てられれば、その開始アドレスを返す
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        // 残りがノードを書けないほど小さいと、空き領域としてリストに戻せない
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    // 解放されたときにノードを書けるよう、サイズとアラインメントを調整する
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapBackend for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.add_free_region(self.heap_start + self.heap_size, by);
+        self.heap_size += by;
+    }
+
+    // アラインメントのために捨てた隙間も使用中として数える
+    fn usage(&self) -> HeapUsage {
+        let mut free = 0;
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            free += region.size;
+            current = region;
+        }
+        HeapUsage {
+            used: self.heap_size - free,
+            free,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+        let mut allocator = self.lock();
+
+        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            // 残りはリストに戻す(アラインメントのために空けた先頭の隙間は捨てる)
+            let excess_size = region.end_addr() - alloc_end;
+            if excess_size > 0 {
+                allocator.add_free_region(alloc_end, excess_size);
+            }
+            alloc_start as *mut u8
+        } else {
+            null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.lock().add_free_region(ptr as usize, size);
+    }
+}
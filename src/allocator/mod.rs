@@ -0,0 +1,415 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, frame,
+    },
+    instructions::interrupts,
+    VirtAddr,
+};
+use linked_list_allocator::Heap;
+use crate::memory;
+
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+pub mod stats;
+#[cfg(feature = "heap_leak_tracking")]
+pub mod leak;
+
+pub use stats::{stats, HeapStats};
+
+// pub struct Dummy;
+
+// // 自作 OS では標準のアロケータが使えないので自前で定義する必要がある
+// // 必ずアロケーションに失敗するダミーのアロケータ定義
+// unsafe impl GlobalAlloc for Dummy {
+//     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+//         null_mut()
+//     }
+
+//     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+//         panic!("dealloc shoud never be called")
+//     }
+// }
+
+// #[global_allocator]
+// static ALLOCATOR: Dummy = Dummy;
+
+// ヒープの実装はフィーチャで選ぶ
+// 指定しなければ linked_list_allocator クレートのものを使う
+#[cfg(any(
+    all(feature = "heap_bump", feature = "heap_linked_list"),
+    all(feature = "heap_bump", feature = "heap_fixed_size_block"),
+    all(feature = "heap_linked_list", feature = "heap_fixed_size_block"),
+))]
+compile_error!("only one of the heap_* features can be enabled");
+
+#[cfg(feature = "heap_bump")]
+type Backend = bump::BumpAllocator;
+#[cfg(feature = "heap_bump")]
+const fn new_backend() -> Backend {
+    bump::BumpAllocator::new()
+}
+
+#[cfg(feature = "heap_linked_list")]
+type Backend = linked_list::LinkedListAllocator;
+#[cfg(feature = "heap_linked_list")]
+const fn new_backend() -> Backend {
+    linked_list::LinkedListAllocator::new()
+}
+
+#[cfg(feature = "heap_fixed_size_block")]
+type Backend = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "heap_fixed_size_block")]
+const fn new_backend() -> Backend {
+    fixed_size_block::FixedSizeBlockAllocator::new()
+}
+
+#[cfg(not(any(
+    feature = "heap_bump", feature = "heap_linked_list", feature = "heap_fixed_size_block"
+)))]
+type Backend = Heap;
+#[cfg(not(any(
+    feature = "heap_bump", feature = "heap_linked_list", feature = "heap_fixed_size_block"
+)))]
+const fn new_backend() -> Backend {
+    Heap::empty()
+}
+
+// 排他制御にスピンロックを使っているが、ロックを持っている間は割込みを止めるので
+// 割込みハンドラ内でアロケートしてもデッドロックしない
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap {
+    backend: Locked::new(new_backend()),
+};
+
+// 足りなくなったらページを追加でマップしてヒープを広げる
+// 割り当てと解放のたびに統計(stats)を更新する
+pub struct KernelHeap {
+    backend: Locked<Backend>,
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let ptr = self.backend.alloc(layout);
+            if !ptr.is_null() {
+                stats::record_alloc(layout);
+                #[cfg(feature = "heap_leak_tracking")]
+                leak::track(ptr, layout);
+                return ptr;
+            }
+            if !grow_heap(layout) {
+                return ptr;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        stats::record_dealloc(layout);
+        #[cfg(feature = "heap_leak_tracking")]
+        leak::untrack(ptr);
+        self.backend.dealloc(ptr, layout);
+    }
+}
+
+// GlobalAlloc は &self しか受け取らないので、ロックで包んで中身を書き換えられるようにする
+// (外部クレートの型に外部クレートのトレイトは実装できないので、そのためのラッパでもある)
+// ロックを持っている間は割込みを止めておくので、割込みハンドラから lock しても待ち続けることはない
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    // ロックが取れなければ待たずにパニックする
+    // これが正しいのは次の2つが成り立っている間だけ:
+    // - CPU は1つしか動かしていない(他の CPU がロックを持っていれば、待てば解放される)
+    // - inner のロックは必ず lock か try_lock で、割込みを止めてから取る
+    //   (割込みを止めずに持てると、持ったまま他のスレッドに切り替わり、そのスレッドが lock するとパニックする)
+    // 複数の CPU を動かすときは、持ち主が自分でなければ待つようにする必要がある
+    pub fn lock(&self) -> LockedGuard<'_, A> {
+        let interrupts = InterruptsDisabled::new();
+        // 割込みを止めている間は他のスレッドに切り替わらないので、ロックが取れないのは
+        // ロックを持ったまま同じ CPU の上でもう一度 lock したとき(待っても解放されない)
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => panic!("re-entrant lock of Locked<{}>", core::any::type_name::<A>()),
+        };
+        LockedGuard { guard, _interrupts: interrupts }
+    }
+
+    // ロックを持ったまま同じ CPU の上でもう一度取ろうとしたときは、待たずに None を返す
+    pub fn try_lock(&self) -> Option<LockedGuard<'_, A>> {
+        let interrupts = InterruptsDisabled::new();
+        let guard = self.inner.try_lock()?;
+        Some(LockedGuard { guard, _interrupts: interrupts })
+    }
+}
+
+// フィールドは宣言順に drop されるので、ロックを解放してから割込みを元に戻す
+pub struct LockedGuard<'a, A> {
+    guard: spin::MutexGuard<'a, A>,
+    _interrupts: InterruptsDisabled,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+// 割込みを止め、drop されたときに止める前の状態に戻す
+struct InterruptsDisabled {
+    were_enabled: bool,
+}
+
+impl InterruptsDisabled {
+    fn new() -> Self {
+        let were_enabled = interrupts::are_enabled();
+        if were_enabled {
+            interrupts::disable();
+        }
+        InterruptsDisabled { were_enabled }
+    }
+}
+
+impl Drop for InterruptsDisabled {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// ヒープの実装に共通の操作
+pub trait HeapBackend {
+    /// # Safety
+    /// [heap_start, heap_start + heap_size) がマップ済みで、他で使われていないこと
+    /// 1回しか呼んではいけない
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+    /// # Safety
+    /// ヒープの末尾の直後から by バイトがマップ済みで、他で使われていないこと
+    unsafe fn extend(&mut self, by: usize);
+    fn usage(&self) -> HeapUsage;
+}
+
+impl HeapBackend for Heap {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        Heap::init(self, heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        Heap::extend(self, by);
+    }
+
+    fn usage(&self) -> HeapUsage {
+        HeapUsage {
+            used: self.used(),
+            free: self.free(),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_first_fit(layout)
+            .map_or(null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock()
+            .deallocate(core::ptr::NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+// addr を align の倍数に切り上げる(align は 2 のべき乗)
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// メモリアロケートに失敗した場合に呼ばれるハンドラ
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
+// ヒープに使う領域(仮想アドレス)は init_heap で vma から HEAP_MAX_SIZE 分を取っておき、最初は HEAP_SIZE だけマップする
+pub const HEAP_SIZE : usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+// 一度に広げる最小の大きさ
+const HEAP_GROW_STEP: usize = 64 * 1024;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+// vma から取ったヒープの範囲の先頭(init_heap の前は 0)
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+// ヒープを広げられる上限(HEAP_MAX_SIZE 以下)
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+// マップ済みの大きさ
+// 広げている間はロックを持ったままにして、2つのスレッドが同時に広げないようにする
+static HEAP_MAPPED: Locked<usize> = Locked::new(0);
+
+// ヒープの使用量(バイト)
+#[derive(Debug, Clone, Copy)]
+pub struct HeapUsage {
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn heap_usage() -> HeapUsage {
+    ALLOCATOR.backend.lock().usage()
+}
+
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+// 今マップされているヒープの大きさ
+pub fn heap_size() -> usize {
+    *HEAP_MAPPED.lock()
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+// すでにマップした分より小さくしても縮みはしない
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+// 最初の HEAP_SIZE 分をマップしてアロケータを初期化する
+// memory::install の後なら、足りなくなったときに memory::MAPPER と memory::FRAME_ALLOCATOR で広げられる
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapped = HEAP_MAPPED.lock();
+    // 2MiB のページでマップできるよう、先頭を 2MiB にそろえる
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let area = memory::vma::reserve(
+        memory::vma::Purpose::Heap, "heap", HEAP_MAX_SIZE as u64, HUGE_PAGE_SIZE as u64, flags,
+    ).expect("no virtual address space left for the heap");
+    let heap_start = area.start.as_u64() as usize;
+    HEAP_START.store(heap_start, Ordering::Relaxed);
+
+    let size = map_heap_pages(heap_start, HEAP_SIZE, mapper, frame_allocator)?;
+    assert_eq!(size, HEAP_SIZE);
+
+    // アロケータに確保したメモリ領域の情報を伝えて初期化する
+    unsafe {
+        HeapBackend::init(&mut *ALLOCATOR.backend.lock(), heap_start, HEAP_SIZE);
+    }
+    *mapped = HEAP_SIZE;
+
+    Ok(())
+}
+
+// layout を割り当てられるようにヒープを広げる
+// 少しでも広げられたら true を返す(それでも足りなければ呼び出し側がもう一度呼ぶ)
+fn grow_heap(layout: Layout) -> bool {
+    let mut mapped = HEAP_MAPPED.lock();
+    let heap_start = heap_start();
+    // アラインメントのための隙間やアロケータの管理領域の分も余分に取る
+    let mut wanted = align_up((layout.size() + layout.align()).max(HEAP_GROW_STEP), 4096);
+    // ヒープが 2MiB を超えるところからは 2MiB の境界まで広げる
+    // ヒープの先頭は 2MiB にそろえてあるので、それ以降はいつも 2MiB のページでマップできる
+    if *mapped + wanted >= HUGE_PAGE_SIZE {
+        wanted = align_up(heap_start + *mapped + wanted, HUGE_PAGE_SIZE) - (heap_start + *mapped);
+    }
+    let additional = wanted.min(heap_limit().saturating_sub(*mapped));
+    if additional == 0 {
+        return false;
+    }
+
+    // ページテーブルやフレームアロケータのロックは割込みを止めて取るので、取れないのは
+    // 同じ CPU の上でロックを持ったままアロケートされたとき(待つとデッドロックする)だけ
+    // そのときは諦める
+    let mut mapper = match memory::MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frame_allocator = match memory::FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        // まだ memory::install されていない
+        _ => return false,
+    };
+
+    // 物理メモリが足りなくなっても、途中までマップできた分は使う
+    // そろっているところは大きなページでマップされる
+    let start = VirtAddr::new((heap_start + *mapped) as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let grown = match memory::mapping::map_anonymous(mapper, start, additional as u64, flags, frame_allocator) {
+        Ok(size) => size as usize,
+        Err(_) => return false,
+    };
+    if grown == 0 {
+        return false;
+    }
+    unsafe { ALLOCATOR.backend.lock().extend(grown) };
+    *mapped += grown;
+    true
+}
+
+// [start, start + size) にフレームをマップし、マップできた大きさを返す
+// 1ページもマップできなければエラーにする
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, MapToError<Size4KiB>> {
+    // 指定した場所(仮想アドレス)とサイズに対応するページ情報を作る
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    // ヒープ用に使うページを、順番に物理フレームのどこかに割当てていく
+    let mut mapped = 0;
+    for page in page_range {
+        // フレームアロケータを使って未使用の物理フレームを確保
+        let result = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                unsafe {
+                    // マップを更新して TLB をクリアする
+                    // ここでもフレームアロケータを渡しているのは、L2 以上のページテーブルを更新する可能性があるから
+                    mapper.map_to(page, frame, flags, frame_allocator).map(|flush| flush.flush())
+                }
+            });
+        match result {
+            Ok(()) => mapped += 4096,
+            Err(error) if mapped == 0 => return Err(error),
+            Err(_) => break,
+        }
+    }
+    Ok(mapped)
+}
@@ -0,0 +1,84 @@
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// ヒープの使われ方の統計
+// アロケートのたびに更新するので、ロックを使わずアトミック変数で数える
+
+// 大きさごとの割り当て回数を数える区間の数
+// i 番目は 2^(i+3) バイト以下のもの(最後だけはそれより大きいものすべて)
+pub const HISTOGRAM_BUCKETS: usize = 10;
+
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static HISTOGRAM: [AtomicUsize; HISTOGRAM_BUCKETS] = [const { AtomicUsize::new(0) }; HISTOGRAM_BUCKETS];
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    // 要求された大きさの合計(アロケータの管理領域やアラインメントの隙間は含まない)
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl HeapStats {
+    // まだ解放されていない割り当ての数
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.frees
+    }
+}
+
+pub fn stats() -> HeapStats {
+    let mut histogram = [0; HISTOGRAM_BUCKETS];
+    for (count, bucket) in histogram.iter_mut().zip(HISTOGRAM.iter()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+    HeapStats {
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        histogram,
+    }
+}
+
+// i 番目の区間に入る大きさの上限(最後の区間は上限なし)
+pub fn bucket_limit(index: usize) -> Option<usize> {
+    if index + 1 < HISTOGRAM_BUCKETS {
+        Some(8 << index)
+    } else {
+        None
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    (0..HISTOGRAM_BUCKETS - 1)
+        .find(|&index| size <= 8 << index)
+        .unwrap_or(HISTOGRAM_BUCKETS - 1)
+}
+
+pub(super) fn record_alloc(layout: Layout) {
+    let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK_BYTES.fetch_max(in_use, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    HISTOGRAM[bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_dealloc(layout: Layout) {
+    BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+    FREES.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_bucket() {
+    assert_eq!(bucket(1), 0);
+    assert_eq!(bucket(8), 0);
+    assert_eq!(bucket(9), 1);
+    assert_eq!(bucket(4096), 9);
+    assert_eq!(bucket(1 << 20), HISTOGRAM_BUCKETS - 1);
+    assert_eq!(bucket_limit(0), Some(8));
+    assert_eq!(bucket_limit(HISTOGRAM_BUCKETS - 1), None);
+}
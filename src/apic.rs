@@ -0,0 +1,207 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    PhysAddr,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+};
+use crate::interrupts::InterruptIndex;
+use crate::memory::vma::{self, Area, Purpose};
+
+// レガシーな 8259 PIC の代わりに、Local APIC と I/O APIC で割込みを配送する
+// Local APIC は CPU ごとにあり、タイマと EOI の受付を担当する
+// I/O APIC はデバイスからの IRQ を受け取り、どの CPU のどのベクタに届けるかを決める(リダイレクションテーブル)
+
+// APIC のレジスタは MMIO なので、vma から取った仮想アドレスにマップする
+// 本来は ACPI の MADT を読んで調べるべきだが、qemu を含めほとんどの環境でこのアドレスになっている
+const IOAPIC_PHYS_ADDR: u64 = 0xfec0_0000;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC のレジスタ(ベースからのオフセット)
+const LAPIC_ID: usize = 0x020;
+const LAPIC_TPR: usize = 0x080;
+const LAPIC_EOI: usize = 0x0b0;
+const LAPIC_SVR: usize = 0x0f0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+// 分周比 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+// キャリブレーションはしていないので、周期は CPU(バスクロック)次第
+const TIMER_INITIAL_COUNT: u32 = 0x20_0000;
+
+// I/O APIC は IOREGSEL にレジスタ番号を書いてから IOWIN で読み書きする間接アクセス
+const IOAPIC_IOREGSEL: usize = 0x00;
+const IOAPIC_IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+// ISA の IRQ 番号(PIC のときと同じ番号を I/O APIC の入力ピンとしても使う)
+const KEYBOARD_IRQ: u8 = 1;
+const SERIAL_IRQ: u8 = 4;
+
+// APIC が未使用の割込みを受けたときに使うベクタ
+// 下位 4 ビットが 1 になっている必要がある
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IOAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// APIC モードで動いているか(EOI の送り先を決めるのに使う)
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// CPUID で APIC が載っているかを確認する
+pub fn is_supported() -> bool {
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+// Local APIC と I/O APIC をマップして初期化し、以降の割込みを APIC 経由にする
+// 呼び出し前に PIC の再マップ(blog_os::init)が終わっている必要がある
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::instructions::interrupts;
+
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    let apic_base = unsafe { apic_base_msr.read() };
+    // 下位 12 ビットはフラグなので、それより上が Local APIC の物理アドレス
+    let lapic_phys = apic_base & !0xfff;
+
+    let lapic = reserve_mmio("local apic");
+    let ioapic = reserve_mmio("io apic");
+
+    interrupts::without_interrupts(|| {
+        map_mmio(&lapic, lapic_phys, mapper, frame_allocator)?;
+        map_mmio(&ioapic, IOAPIC_PHYS_ADDR, mapper, frame_allocator)?;
+        LAPIC_BASE.store(lapic.start.as_u64(), Ordering::Release);
+        IOAPIC_BASE.store(ioapic.start.as_u64(), Ordering::Release);
+
+        unsafe {
+            disable_legacy_pic();
+
+            apic_base_msr.write(apic_base | APIC_BASE_ENABLE);
+            init_local_apic();
+            init_io_apic();
+        }
+
+        ENABLED.store(true, Ordering::Release);
+        Ok(())
+    })
+}
+
+// 割込み処理の終了を Local APIC に通知する
+// EOI レジスタには何を書いてもよい
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+// レジスタ1ページ分の仮想アドレスを vma から取る
+fn reserve_mmio(name: &'static str) -> Area {
+    // デバイスのレジスタなのでキャッシュさせない
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    vma::reserve(Purpose::Mmio, name, 4096, 4096, flags)
+        .expect("no virtual address space left for the apic registers")
+}
+
+fn map_mmio(
+    area: &Area,
+    phys: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(area.start);
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    unsafe {
+        mapper.map_to(page, frame, area.flags, frame_allocator)?.flush();
+    }
+    Ok(())
+}
+
+// PIC の全 IRQ をマスクして、APIC と二重に割込みが来ないようにする
+// 再マップ自体は済んでいるので、スプリアス割込みが例外のベクタに化けることはない
+unsafe fn disable_legacy_pic() {
+    use x86_64::instructions::port::Port;
+
+    Port::<u8>::new(0x21).write(0xff);
+    Port::<u8>::new(0xa1).write(0xff);
+}
+
+unsafe fn init_local_apic() {
+    // ソフトウェア的に有効化しつつ、スプリアス割込みのベクタを設定
+    lapic_write(LAPIC_SVR, SVR_APIC_ENABLE | u32::from(SPURIOUS_INTERRUPT_VECTOR));
+    // 全ての優先度の割込みを受け付ける
+    lapic_write(LAPIC_TPR, 0);
+    // LINT0/1 は PIC や NMI の配線につながっているが使わないのでマスクする
+    lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    lapic_write(LAPIC_LVT_LINT1, LVT_MASKED);
+
+    // PIT の代わりに Local APIC タイマで周期的にタイマ割込みを起こす
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()));
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, TIMER_INITIAL_COUNT);
+}
+
+unsafe fn init_io_apic() {
+    // バージョンレジスタの 16~23 ビットがリダイレクションエントリ数 - 1
+    let max_entry = (ioapic_read(IOAPIC_VERSION) >> 16) & 0xff;
+    for irq in 0..=max_entry {
+        set_redirection(irq as u8, 0, 0, true);
+    }
+
+    // 使う IRQ だけ、この CPU の Local APIC に届ける
+    let lapic_id = (lapic_read(LAPIC_ID) >> 24) as u8;
+    set_redirection(KEYBOARD_IRQ, InterruptIndex::Keyboard.as_u8(), lapic_id, false);
+    set_redirection(SERIAL_IRQ, InterruptIndex::Serial.as_u8(), lapic_id, false);
+}
+
+// リダイレクションエントリは 64 ビットで、2 つの 32 ビットレジスタに分かれている
+// 下位: ベクタ・配送モード・マスクなど、上位: 宛先の APIC ID
+unsafe fn set_redirection(irq: u8, vector: u8, destination: u8, masked: bool) {
+    let register = IOAPIC_REDIRECTION_TABLE + u32::from(irq) * 2;
+    let mut low = u32::from(vector);
+    if masked {
+        low |= LVT_MASKED;
+    }
+    ioapic_write(register + 1, u32::from(destination) << 24);
+    ioapic_write(register, low);
+}
+
+unsafe fn lapic_read(offset: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn lapic_write(offset: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+unsafe fn ioapic_read(register: u32) -> u32 {
+    let base = IOAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::write_volatile((base + IOAPIC_IOREGSEL) as *mut u32, register);
+    core::ptr::read_volatile((base + IOAPIC_IOWIN) as *const u32)
+}
+
+unsafe fn ioapic_write(register: u32, value: u32) {
+    let base = IOAPIC_BASE.load(Ordering::Acquire) as usize;
+    core::ptr::write_volatile((base + IOAPIC_IOREGSEL) as *mut u32, register);
+    core::ptr::write_volatile((base + IOAPIC_IOWIN) as *mut u32, value);
+}
@@ -0,0 +1,256 @@
+use bootloader::bootinfo::MemoryMap;
+use core::fmt;
+use spin::Once;
+use x86_64::VirtAddr;
+use crate::elf::{Symbol, SymbolTable};
+use crate::{println, serial_println};
+
+// フレームポインタ(rbp)をたどるスタックトレースと、カーネルのシンボルテーブルによる名前解決
+// 各フレームは [rbp] に呼び出し元の rbp、[rbp + 8] に戻りアドレスを持っている
+// (ターゲットの設定で frame-pointer を always にしてあるので、どの関数もこの形のフレームを作る)
+//
+// ブートローダはカーネルの ELF ファイルを丸ごと物理メモリに読み込んでいるので(memory::kernel_image)、
+// init でそこから .symtab を探しておき、以降は戻りアドレスを関数名 + オフセットで表示する
+// init の前やシンボルが strip されているときはアドレスだけを表示する
+
+// たどるフレームの数の上限
+const MAX_DEPTH: usize = 32;
+// 1つのスタックフレームの大きさの上限
+// これより離れた rbp はフレームポインタではないとみなしてたどるのをやめる
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+static KERNEL_SYMBOLS: Once<SymbolTable<'static>> = Once::new();
+
+// 物理メモリ全体がマップされた後に呼ぶ
+pub fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    let symbols = crate::memory::kernel_image(memory_map, physical_memory_offset)
+        .and_then(|kernel| kernel.symbol_table());
+    if let Some(symbols) = symbols {
+        KERNEL_SYMBOLS.call_once(|| symbols);
+    }
+}
+
+// addr を含むカーネルの関数と、その先頭からのオフセット
+pub fn lookup(addr: u64) -> Option<(Symbol<'static>, u64)> {
+    KERNEL_SYMBOLS.r#try()?.lookup(addr)
+}
+
+// 今の rbp
+// 呼び出し元に展開されるので、呼び出した関数のフレームを指す
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+// rbp から呼び出し元に向かって戻りアドレスを順に f に渡す
+// f が false を返すか、フレームポインタらしくない値が出てきたら止める
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64) -> bool) {
+    loop {
+        // 非正規アドレスを読むと一般保護例外に、マップされていないアドレスを読むとページフォルトになるので、読む前に確かめる
+        // (ページフォルトの報告中に読むと、同じ IST のスタックの上でもう一度ページフォルトが起きてしまう)
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            return;
+        }
+        let (next, return_address) = unsafe {
+            (*(rbp as *const u64), *((rbp + 8) as *const u64))
+        };
+        if return_address == 0 || !f(return_address) {
+            return;
+        }
+        // 呼び出し元のフレームはスタックのより上(大きいアドレス)にある
+        // スレッドの最初のフレームは rbp が 0 なのでここで止まる
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+// addr を読んでもフォルトしないか
+// ページテーブルは物理メモリのマッピング経由で読むので、memory::init の前は何も読まない
+fn is_mapped(addr: u64) -> bool {
+    if crate::memory::physical_memory_offset().as_u64() == 0 {
+        return false;
+    }
+    match VirtAddr::try_new(addr) {
+        Ok(addr) => crate::memory::inspect::translate(addr).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// 呼び出し元からのバックトレースを VGA とシリアルに表示する
+#[inline(never)]
+pub fn print() {
+    print_frames(None, frame_pointer());
+}
+
+// 例外が起きた場所(rip と、そのときの rbp)からのバックトレースを表示する
+pub fn print_from(rip: u64, rbp: u64) {
+    print_frames(Some(rip), rbp);
+}
+
+fn print_frames(rip: Option<u64>, rbp: u64) {
+    output(format_args!("backtrace:"));
+    let mut depth = 0;
+    if let Some(rip) = rip {
+        print_frame(depth, rip, rip);
+        depth += 1;
+    }
+    walk(rbp, |return_address| {
+        // 戻りアドレスは call の次の命令なので、関数の末尾の call だと次の関数を指してしまう
+        // 1 バイト戻して call 命令の中で名前を引く
+        print_frame(depth, return_address, return_address - 1);
+        depth += 1;
+        depth < MAX_DEPTH
+    });
+}
+
+fn print_frame(depth: usize, addr: u64, lookup_addr: u64) {
+    match lookup(lookup_addr) {
+        Some((symbol, offset)) => output(format_args!(
+            "  {:>2}: {:#018x} {}+{:#x}",
+            depth, addr, Demangle(symbol.name), offset + (addr - lookup_addr)
+        )),
+        None => output(format_args!("  {:>2}: {:#018x} <unknown>", depth, addr)),
+    }
+}
+
+fn output(args: fmt::Arguments) {
+    println!("{}", args);
+    serial_println!("{}", args);
+}
+
+// Rust のシンボル名を読める形にして表示する
+// 旧来の形式(_ZN...E)だけを戻し、それ以外の名前はそのまま表示する
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = match legacy_path(self.0) {
+            Some(path) => path,
+            None => return f.write_str(self.0),
+        };
+        for (i, ident) in Identifiers(path).enumerate() {
+            // 最後の要素はハッシュ(h + 16 桁の16進数)なので表示しない
+            if is_hash(ident) {
+                break;
+            }
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_identifier(f, ident)?;
+        }
+        Ok(())
+    }
+}
+
+// _ZN{長さ}{識別子}...E の、_ZN と E の間
+// 末尾に .llvm.1234 のような接尾辞が付くことがある
+fn legacy_path(symbol: &str) -> Option<&str> {
+    let inner = symbol.strip_prefix("_ZN")?;
+    let mut end = 0;
+    loop {
+        let rest = inner.get(end..)?;
+        if let Some(suffix) = rest.strip_prefix('E') {
+            if !suffix.is_empty() && !suffix.starts_with('.') {
+                return None;
+            }
+            return Some(&inner[..end]);
+        }
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().ok()?;
+        end = end.checked_add(digits)?.checked_add(len)?;
+    }
+}
+
+// {長さ}{識別子} の並びから識別子を順に取り出す
+struct Identifiers<'a>(&'a str);
+
+impl<'a> Iterator for Identifiers<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = self.0[..digits].parse().ok()?;
+        let ident = self.0.get(digits..digits + len)?;
+        self.0 = &self.0[digits + len..];
+        Some(ident)
+    }
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17
+        && ident.starts_with('h')
+        && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// 識別子の中のエスケープ($LT$ や .. など)を元の文字に戻す
+fn write_identifier(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+    // $ で始まる識別子には _ が前に付けられている
+    let mut rest = match ident.strip_prefix("_$") {
+        Some(_) => &ident[1..],
+        None => ident,
+    };
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = tail;
+        } else if let Some(escape) = rest.strip_prefix('$').and_then(|tail| tail.split_once('$')) {
+            let (code, tail) = escape;
+            match unescape(code) {
+                Some(c) => write!(f, "{}", c)?,
+                None => write!(f, "${}$", code)?,
+            }
+            rest = tail;
+        } else {
+            let len = rest[1..].find(['$', '.']).map_or(rest.len(), |i| i + 1);
+            f.write_str(&rest[..len])?;
+            rest = &rest[len..];
+        }
+    }
+    Ok(())
+}
+
+fn unescape(code: &str) -> Option<char> {
+    match code {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ => {
+            let hex = code.strip_prefix('u')?;
+            char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+        }
+    }
+}
+
+#[test_case]
+fn test_demangle() {
+    let demangled = |symbol| crate::FmtBuffer::format(format_args!("{}", Demangle(symbol)));
+    assert_eq!(
+        demangled("_ZN4core9panicking5panic17h0123456789abcdefE").as_str(),
+        "core::panicking::panic"
+    );
+    assert_eq!(
+        demangled("_ZN59_$LT$blog_os..elf..ElfError$u20$as$u20$core..fmt..Debug$GT$3fmt17hfedcba9876543210E").as_str(),
+        "<blog_os::elf::ElfError as core::fmt::Debug>::fmt"
+    );
+    assert_eq!(demangled("exception_common").as_str(), "exception_common");
+}
+
+#[test_case]
+fn test_walk_finds_caller() {
+    let mut frames = 0;
+    walk(frame_pointer(), |_| {
+        frames += 1;
+        true
+    });
+    // 少なくともテストランナーからの呼び出しはたどれる
+    assert!(frames > 0);
+}
@@ -0,0 +1,302 @@
+// ELF64 の実行ファイルを読む
+// ロードに必要なのはプログラムヘッダ(どこに何をロードするか)とエントリポイント
+// セクションヘッダはバックトレースのためにシンボルテーブルを探すときだけ使う
+// バイト列はアラインされているとは限らないので、構造体にキャストせず1フィールドずつ読み出す
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+pub const PT_LOAD: u32 = 1;
+
+// プログラムヘッダの p_flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// セクションヘッダの sh_type
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+
+// シンボルの種類(st_info の下位 4 ビット)
+pub const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    // 64 ビット・リトルエンディアンの x86_64 向けではない
+    UnsupportedFormat,
+    // 静的リンクされた実行ファイル(ET_EXEC)以外
+    NotExecutable,
+    BadProgramHeader,
+    BadSectionHeader,
+    // セグメントの中身がファイルの外を指している
+    SegmentOutOfBounds,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.p_flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.p_flags & PF_X != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SectionHeader {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_entsize: u64,
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+    shoff: usize,
+    shnum: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    // ヘッダを検証する
+    // PT_LOAD のセグメントは、ファイルの範囲に収まっていることまで確認する
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT
+            || read_u16(data, 18) != EM_X86_64
+        {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let phoff = read_u64(data, 32) as usize;
+        let phentsize = usize::from(read_u16(data, 54));
+        let phnum = usize::from(read_u16(data, 56));
+        if phentsize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        match phnum.checked_mul(PROGRAM_HEADER_SIZE).and_then(|size| size.checked_add(phoff)) {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(ElfError::BadProgramHeader),
+        }
+
+        // セクションヘッダはなくてもよい(shnum が 0)
+        let shoff = read_u64(data, 40) as usize;
+        let shnum = usize::from(read_u16(data, 60));
+        if shnum != 0 {
+            if usize::from(read_u16(data, 58)) != SECTION_HEADER_SIZE {
+                return Err(ElfError::BadSectionHeader);
+            }
+            match shnum.checked_mul(SECTION_HEADER_SIZE).and_then(|size| size.checked_add(shoff)) {
+                Some(end) if end <= data.len() => {}
+                _ => return Err(ElfError::BadSectionHeader),
+            }
+        }
+
+        let elf = ElfFile { data, entry: read_u64(data, 24), phoff, phnum, shoff, shnum };
+        for header in elf.program_headers().filter(|h| h.is_load()) {
+            if header.p_filesz > header.p_memsz {
+                return Err(ElfError::BadProgramHeader);
+            }
+            match header.p_offset.checked_add(header.p_filesz) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => return Err(ElfError::SegmentOutOfBounds),
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.phoff;
+        (0..self.phnum).map(move |i| {
+            let base = phoff + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                p_type: read_u32(data, base),
+                p_flags: read_u32(data, base + 4),
+                p_offset: read_u64(data, base + 8),
+                p_vaddr: read_u64(data, base + 16),
+                p_filesz: read_u64(data, base + 32),
+                p_memsz: read_u64(data, base + 40),
+                p_align: read_u64(data, base + 48),
+            }
+        })
+    }
+
+    // ファイルに含まれているセグメントの中身
+    // p_memsz のうち残りの部分(.bss など)は 0 で埋める
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        let start = header.p_offset as usize;
+        &self.data[start..start + header.p_filesz as usize]
+    }
+
+    pub fn section_headers(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let data = self.data;
+        let shoff = self.shoff;
+        (0..self.shnum).map(move |i| {
+            let base = shoff + i * SECTION_HEADER_SIZE;
+            SectionHeader {
+                sh_name: read_u32(data, base),
+                sh_type: read_u32(data, base + 4),
+                sh_flags: read_u64(data, base + 8),
+                sh_addr: read_u64(data, base + 16),
+                sh_offset: read_u64(data, base + 24),
+                sh_size: read_u64(data, base + 32),
+                sh_link: read_u32(data, base + 40),
+                sh_entsize: read_u64(data, base + 56),
+            }
+        })
+    }
+
+    // ファイルに含まれているセクションの中身(ファイルの外を指していれば None)
+    pub fn section_data(&self, header: &SectionHeader) -> Option<&'a [u8]> {
+        if header.sh_type == SHT_NOBITS {
+            return None;
+        }
+        let start = header.sh_offset as usize;
+        let end = start.checked_add(header.sh_size as usize)?;
+        self.data.get(start..end)
+    }
+
+    // .symtab と、そこから名前を引く文字列テーブル(strip されていれば None)
+    pub fn symbol_table(&self) -> Option<SymbolTable<'a>> {
+        let symtab = self.section_headers().find(|h| h.sh_type == SHT_SYMTAB)?;
+        let strtab = self.section_headers().nth(symtab.sh_link as usize)?;
+        Some(SymbolTable {
+            symbols: self.section_data(&symtab)?,
+            strings: self.section_data(&strtab)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: u64,
+    pub size: u64,
+    pub kind: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symbols: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol<'a>> + 'a {
+        let symbols = self.symbols;
+        let strings = self.strings;
+        (0..symbols.len() / SYMBOL_SIZE).map(move |i| {
+            let base = i * SYMBOL_SIZE;
+            Symbol {
+                name: read_str(strings, read_u32(symbols, base) as usize),
+                value: read_u64(symbols, base + 8),
+                size: read_u64(symbols, base + 16),
+                kind: symbols[base + 4] & 0xf,
+            }
+        })
+    }
+
+    // addr を含む関数のシンボルと、その先頭からのオフセット
+    pub fn lookup(&self, addr: u64) -> Option<(Symbol<'a>, u64)> {
+        self.symbols()
+            .find(|symbol| {
+                symbol.kind == STT_FUNC && symbol.value <= addr && addr < symbol.value + symbol.size
+            })
+            .map(|symbol| (symbol, addr - symbol.value))
+    }
+}
+
+// offset から NUL までの文字列(UTF-8 でなければ空文字列)
+fn read_str(data: &[u8], offset: usize) -> &str {
+    let bytes = data.get(offset..).unwrap_or(&[]);
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[test_case]
+fn test_parse_user_hello() {
+    let elf = ElfFile::parse(crate::usermode::HELLO_ELF).expect("failed to parse hello.elf");
+    assert_eq!(elf.entry(), 0x0000_0800_0000_0000);
+    let text = elf.program_headers()
+        .find(|h| h.is_load() && h.is_executable())
+        .expect("no text segment");
+    assert!(!text.is_writable());
+    assert!(elf.program_headers().any(|h| h.is_load() && h.is_writable() && h.p_memsz > h.p_filesz));
+}
+
+#[test_case]
+fn test_reject_bad_magic() {
+    let mut data = [0u8; ELF_HEADER_SIZE];
+    data[0..4].copy_from_slice(b"\x7fBAD");
+    assert_eq!(ElfFile::parse(&data).err(), Some(ElfError::BadMagic));
+    assert_eq!(ElfFile::parse(&data[..10]).err(), Some(ElfError::TooShort));
+}
+
+#[test_case]
+fn test_section_headers_of_user_hello() {
+    let elf = ElfFile::parse(crate::usermode::HELLO_ELF).expect("failed to parse hello.elf");
+    // .bss はファイルに中身を持たない
+    assert!(elf.section_headers()
+        .any(|h| h.sh_type == SHT_NOBITS && elf.section_data(&h).is_none()));
+}
@@ -0,0 +1,426 @@
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode};
+use crate::{gdt, println};
+
+// CPU 例外(0~31 番)の共通の入口
+// x86-interrupt の関数では汎用レジスタが見えないので、例外ごとの小さなアセンブリの入口(スタブ)から
+// 全レジスタをスタックに積んで exception_dispatch を呼び、どの例外も同じ形式で報告する
+//
+// スタブはエラーコードを積まない例外ではダミーの 0 を積み、どちらも例外番号を積んでから共通部分に飛ぶ
+// スタブは 16 バイトごとに並べるので、例外番号からアドレスが決まる
+
+const STUB_SIZE: u64 = 16;
+
+// スタブと exception_common がスタックに積んだ内容(アドレスの小さい順)
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // エラーコードのない例外では 0
+    pub error_code: u64,
+    // ここから下は CPU が積んだもの
+    pub frame: InterruptStackFrameValue,
+}
+
+impl ExceptionContext {
+    // 例外が起きたときの特権レベルがリング 3 か
+    pub fn from_user_mode(&self) -> bool {
+        self.frame.code_segment & 0b11 == 3
+    }
+}
+
+struct ExceptionInfo {
+    name: &'static str,
+    mnemonic: &'static str,
+    has_error_code: bool,
+}
+
+const fn info(name: &'static str, mnemonic: &'static str, has_error_code: bool) -> Option<ExceptionInfo> {
+    Some(ExceptionInfo { name, mnemonic, has_error_code })
+}
+
+// アーキテクチャで定義されている例外(None は予約されている番号)
+const EXCEPTIONS: [Option<ExceptionInfo>; 32] = [
+    info("DIVIDE ERROR", "#DE", false),
+    info("DEBUG", "#DB", false),
+    info("NON-MASKABLE INTERRUPT", "NMI", false),
+    info("BREAKPOINT", "#BP", false),
+    info("OVERFLOW", "#OF", false),
+    info("BOUND RANGE EXCEEDED", "#BR", false),
+    info("INVALID OPCODE", "#UD", false),
+    info("DEVICE NOT AVAILABLE", "#NM", false),
+    info("DOUBLE FAULT", "#DF", true),
+    // 9 番(コプロセッサセグメントオーバーラン)は 64 ビットモードでは起きない
+    None,
+    info("INVALID TSS", "#TS", true),
+    info("SEGMENT NOT PRESENT", "#NP", true),
+    info("STACK-SEGMENT FAULT", "#SS", true),
+    info("GENERAL PROTECTION FAULT", "#GP", true),
+    info("PAGE FAULT", "#PF", true),
+    None,
+    info("X87 FLOATING-POINT EXCEPTION", "#MF", false),
+    info("ALIGNMENT CHECK", "#AC", true),
+    info("MACHINE CHECK", "#MC", false),
+    info("SIMD FLOATING-POINT EXCEPTION", "#XM", false),
+    info("VIRTUALIZATION EXCEPTION", "#VE", false),
+    info("CONTROL PROTECTION EXCEPTION", "#CP", true),
+    None, None, None, None, None, None,
+    info("HYPERVISOR INJECTION EXCEPTION", "#HV", false),
+    info("VMM COMMUNICATION EXCEPTION", "#VC", true),
+    info("SECURITY EXCEPTION", "#SX", true),
+    None,
+];
+
+// 全ての例外のハンドラを IDT に登録する
+// 割込みの途中でも起きる NMI・マシンチェック、スタックが壊れていても報告したいダブルフォルト・ページフォルトは
+// それぞれ専用のスタック(スタックの確保自体は gdt.rs で実施)で受ける
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0));
+        idt.debug.set_handler_addr(stub(1));
+        idt.non_maskable_interrupt.set_handler_addr(stub(2))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub(3));
+        idt.overflow.set_handler_addr(stub(4));
+        idt.bound_range_exceeded.set_handler_addr(stub(5));
+        idt.invalid_opcode.set_handler_addr(stub(6));
+        idt.device_not_available.set_handler_addr(stub(7));
+        idt.double_fault.set_handler_addr(stub(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub(10));
+        idt.segment_not_present.set_handler_addr(stub(11));
+        idt.stack_segment_fault.set_handler_addr(stub(12));
+        idt.general_protection_fault.set_handler_addr(stub(13));
+        // ページングの有効化はブートローダで実施されている
+        idt.page_fault.set_handler_addr(stub(14))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_addr(stub(16));
+        idt.alignment_check.set_handler_addr(stub(17));
+        idt.machine_check.set_handler_addr(stub(18))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub(19));
+        idt.virtualization.set_handler_addr(stub(20));
+        idt.cp_protection_exception.set_handler_addr(stub(21));
+        idt.hv_injection_exception.set_handler_addr(stub(28));
+        idt.vmm_communication_exception.set_handler_addr(stub(29));
+        idt.security_exception.set_handler_addr(stub(30));
+    }
+}
+
+fn stub(vector: u64) -> VirtAddr {
+    VirtAddr::new(exception_stubs as *const () as u64 + vector * STUB_SIZE)
+}
+
+// exception_common から呼ばれる
+// 戻ると積んだレジスタを元に戻して iretq するので、context を書き換えれば戻り先も変えられる
+extern "C" fn exception_dispatch(context: &mut ExceptionContext) {
+    #[cfg(test)]
+    if test_hook::catch(context) {
+        return;
+    }
+
+    match context.vector {
+        // 報告だけして処理を続ける
+        1 | 3 => report(context),
+        2 => {
+            // NMI は割込み禁止中にも届くので、割り込まれた側が画面のロックを持っていたら表示を諦める
+            // (CPU は1つなので、ここで取れればハンドラが終わるまで他に取られることはない)
+            if crate::vga_buffer::WRITER.try_lock().is_some() {
+                report(context);
+            }
+        }
+        14 if crate::memory::fault::resolve(
+            Cr2::read(), PageFaultErrorCode::from_bits_truncate(context.error_code)
+        ) => {
+            // 登録されたリゾルバがページをマップしたので、フォルトした命令からやり直す
+        }
+        _ => {
+            // スタックがあふれてガードページに触ったときは、どのスタックかを報告する
+            // ダブルフォルトのときも、CR2 には最後のページフォルトのアドレスが残っている
+            if matches!(context.vector, 8 | 14) {
+                if let Some(name) = crate::memory::stack::guard_page_owner(Cr2::read()) {
+                    report(context);
+                    panic!("EXCEPTION: stack overflow in {}", name);
+                }
+            }
+            report(context);
+            // ユーザモード(リング 3)で起きた例外なら、カーネルは止めずにユーザプログラムだけを終了させる
+            // ただしダブルフォルトとマシンチェックは中断(abort)クラスで、カーネルの状態も信用できないので止める
+            if context.from_user_mode() && !matches!(context.vector, 8 | 18) {
+                println!("killed the user program");
+                crate::usermode::kill();
+            }
+            panic!("EXCEPTION: {} in kernel", name(context.vector));
+        }
+    }
+}
+
+fn name(vector: u64) -> &'static str {
+    match EXCEPTIONS.get(vector as usize) {
+        Some(Some(info)) => info.name,
+        _ => "RESERVED EXCEPTION",
+    }
+}
+
+// 例外の名前、エラーコードの内訳、CPU が積んだフレーム、汎用レジスタ、CR2/CR3 を表示する
+fn report(context: &ExceptionContext) {
+    let vector = context.vector;
+    let info = EXCEPTIONS.get(vector as usize).and_then(|info| info.as_ref());
+    match info {
+        Some(info) => println!("EXCEPTION: {} ({}, vector {})", info.name, info.mnemonic, vector),
+        None => println!("EXCEPTION: RESERVED (vector {})", vector),
+    }
+
+    if info.is_some_and(|info| info.has_error_code) {
+        report_error_code(vector, context.error_code);
+    }
+    println!("{:#?}", context.frame);
+
+    println!("rax={:#018x} rbx={:#018x} rcx={:#018x}", context.rax, context.rbx, context.rcx);
+    println!("rdx={:#018x} rsi={:#018x} rdi={:#018x}", context.rdx, context.rsi, context.rdi);
+    println!("rbp={:#018x} r8 ={:#018x} r9 ={:#018x}", context.rbp, context.r8, context.r9);
+    println!("r10={:#018x} r11={:#018x} r12={:#018x}", context.r10, context.r11, context.r12);
+    println!("r13={:#018x} r14={:#018x} r15={:#018x}", context.r13, context.r14, context.r15);
+
+    // CR2 はページフォルトのアドレスなので、ページフォルトとダブルフォルトのときだけ意味がある
+    if matches!(vector, 8 | 14) {
+        println!("cr2={:#018x}", Cr2::read().as_u64());
+    }
+    let (level_4_table, _) = Cr3::read();
+    println!("cr3={:#018x}", level_4_table.start_address().as_u64());
+
+    // ダブルフォルトとページフォルトのハンドラは別のスタック(IST)で動くので、
+    // パニックのときのバックトレースはスタックの切り替えを越えてたどれない
+    // 例外が起きた場所からのバックトレースをここで表示しておく
+    if matches!(vector, 8 | 14) && !context.from_user_mode() {
+        crate::backtrace::print_from(context.frame.instruction_pointer.as_u64(), context.rbp);
+    }
+}
+
+fn report_error_code(vector: u64, error_code: u64) {
+    match vector {
+        // セグメントセレクタに関係する例外は、原因のセレクタを積む
+        10..=13 => {
+            if error_code == 0 {
+                println!("error code: 0");
+                return;
+            }
+            let selector = SelectorErrorCode(error_code);
+            println!(
+                "error code: {:#x} (index {}, table {}, external {})",
+                error_code, selector.index(), selector.table(), selector.external()
+            );
+        }
+        14 => println!(
+            "error code: {:#x} ({:?})",
+            error_code, PageFaultErrorCode::from_bits_truncate(error_code)
+        ),
+        21 => println!("error code: {:#x} ({})", error_code, control_protection_cause(error_code)),
+        _ => println!("error code: {:#x}", error_code),
+    }
+}
+
+// #TS/#NP/#SS/#GP のエラーコード
+// ビット 0 が外部イベントによるものか、ビット 1~2 が参照したテーブル、ビット 3~15 がそのテーブルのインデックス
+struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    fn external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    fn table(&self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            // ビット 1 が立っていれば IDT
+            _ => "IDT",
+        }
+    }
+
+    fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+fn control_protection_cause(error_code: u64) -> &'static str {
+    match error_code & 0x7fff {
+        1 => "near RET",
+        2 => "far RET/IRET",
+        3 => "ENDBRANCH",
+        4 => "RSTORSSP",
+        5 => "SETSSBSY",
+        _ => "unknown",
+    }
+}
+
+extern "C" {
+    fn exception_stubs();
+}
+
+// エラーコードを積まない例外のスタブ
+macro_rules! stub {
+    ($vector:literal) => {
+        concat!(".balign 16\n", "push 0\n", "push ", $vector, "\n", "jmp exception_common\n")
+    };
+}
+
+// CPU がエラーコードを積む例外のスタブ
+macro_rules! stub_with_error_code {
+    ($vector:literal) => {
+        concat!(".balign 16\n", "push ", $vector, "\n", "jmp exception_common\n")
+    };
+}
+
+core::arch::global_asm!(
+    ".global exception_stubs",
+    ".balign 16",
+    "exception_stubs:",
+    stub!(0), stub!(1), stub!(2), stub!(3), stub!(4), stub!(5), stub!(6), stub!(7),
+    stub_with_error_code!(8), stub!(9),
+    stub_with_error_code!(10), stub_with_error_code!(11),
+    stub_with_error_code!(12), stub_with_error_code!(13), stub_with_error_code!(14),
+    stub!(15), stub!(16), stub_with_error_code!(17), stub!(18), stub!(19), stub!(20),
+    stub_with_error_code!(21),
+    stub!(22), stub!(23), stub!(24), stub!(25), stub!(26), stub!(27), stub!(28),
+    stub_with_error_code!(29), stub_with_error_code!(30), stub!(31),
+    "",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // 呼び出し規約に合わせて DF を落とし、スタックを 16 バイト境界にそろえる
+    // (rbx は callee-saved なので、元のスタックポインタを覚えておくのに使える)
+    "cld",
+    "mov rdi, rsp",
+    "mov rbx, rsp",
+    "and rsp, -16",
+    "call {dispatch}",
+    "mov rsp, rbx",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // 例外番号とエラーコードを捨てる
+    "add rsp, 16",
+    "iretq",
+    dispatch = sym exception_dispatch,
+);
+
+#[test_case]
+fn test_selector_error_code() {
+    // GDT の 2 番目のエントリ
+    let code = SelectorErrorCode(0x10);
+    assert_eq!(code.index(), 2);
+    assert_eq!(code.table(), "GDT");
+    assert!(!code.external());
+    assert_eq!(SelectorErrorCode(0b011).table(), "IDT");
+}
+
+#[test_case]
+fn test_exception_table() {
+    assert!(EXCEPTIONS[14].as_ref().is_some_and(|info| info.has_error_code));
+    assert!(EXCEPTIONS[9].is_none());
+}
+
+// テストで例外を起こし、報告もパニックもせずに指定した場所から再開する
+#[cfg(test)]
+mod test_hook {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use super::ExceptionContext;
+
+    // 0 でなければ、次の例外をここで受けてこのアドレスから再開する
+    pub static RESUME: AtomicU64 = AtomicU64::new(0);
+    pub static VECTOR: AtomicU64 = AtomicU64::new(u64::MAX);
+    pub static ERROR_CODE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    pub fn catch(context: &mut ExceptionContext) -> bool {
+        let resume = RESUME.swap(0, Ordering::SeqCst);
+        if resume == 0 {
+            return false;
+        }
+        VECTOR.store(context.vector, Ordering::SeqCst);
+        ERROR_CODE.store(context.error_code, Ordering::SeqCst);
+        context.frame.instruction_pointer = x86_64::VirtAddr::new(resume);
+        true
+    }
+}
+
+// エラーコードを積まない例外は、スタブがダミーの 0 を積んで例外番号を渡す
+#[test_case]
+fn test_invalid_opcode_goes_through_stub() {
+    use core::sync::atomic::Ordering;
+
+    unsafe {
+        core::arch::asm!(
+            "lea rax, [rip + 2f]",
+            "mov [{resume}], rax",
+            "ud2",
+            "2:",
+            resume = in(reg) test_hook::RESUME.as_ptr(),
+            out("rax") _,
+        );
+    }
+    assert_eq!(test_hook::VECTOR.load(Ordering::SeqCst), 6);
+    assert_eq!(test_hook::ERROR_CODE.load(Ordering::SeqCst), 0);
+}
+
+// CPU が積んだエラーコードがそのまま渡される
+// GDT の範囲外のセレクタを読み込むと、そのセレクタをエラーコードにして #GP が起きる
+#[test_case]
+fn test_general_protection_passes_error_code() {
+    use core::sync::atomic::Ordering;
+
+    unsafe {
+        core::arch::asm!(
+            "lea rax, [rip + 2f]",
+            "mov [{resume}], rax",
+            "mov ax, 0x1230",
+            "mov ds, ax",
+            "2:",
+            resume = in(reg) test_hook::RESUME.as_ptr(),
+            out("rax") _,
+        );
+    }
+    assert_eq!(test_hook::VECTOR.load(Ordering::SeqCst), 13);
+    assert_eq!(test_hook::ERROR_CODE.load(Ordering::SeqCst), 0x1230);
+}
@@ -0,0 +1,79 @@
+use x86_64::instructions::port::Port;
+
+// qemu の fw_cfg(ファームウェア設定)から、起動時に渡されたファイルを読む
+// qemu の起動オプションで -fw_cfg name=opt/blog_os/<名前>,string=<値> と指定すると、
+// カーネルをビルドし直さずに起動時の設定を変えられる
+// セレクタのポートに項目の番号を書いてから、データのポートを1バイトずつ読む
+// qemu 以外ではポートが存在しないので、シグネチャが合わず何も読めない
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SELECT_SIGNATURE: u16 = 0x0000;
+const SELECT_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: [u8; 4] = *b"QEMU";
+// ファイル一覧の各エントリ: 大きさ(u32)、項目の番号(u16)、予約(u16)、名前(56 バイト)
+const FILE_NAME_SIZE: usize = 56;
+
+// qemu の fw_cfg があるか
+pub fn is_present() -> bool {
+    let mut signature = [0; 4];
+    unsafe {
+        select(SELECT_SIGNATURE);
+        read(&mut signature);
+    }
+    signature == SIGNATURE
+}
+
+// name のファイルを buf に読み、読んだ大きさを返す
+// buf に入りきらない分は捨てる
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    if !is_present() {
+        return None;
+    }
+    let (selector, size) = find(name)?;
+    let len = buf.len().min(size as usize);
+    unsafe {
+        select(selector);
+        read(&mut buf[..len]);
+    }
+    Some(len)
+}
+
+// ファイル一覧から name を探し、項目の番号と大きさを返す
+fn find(name: &str) -> Option<(u16, u32)> {
+    unsafe {
+        select(SELECT_FILE_DIR);
+        let count = read_u32_be();
+        for _ in 0..count {
+            let size = read_u32_be();
+            let selector = (read_u32_be() >> 16) as u16;
+            let mut file_name = [0; FILE_NAME_SIZE];
+            read(&mut file_name);
+            let len = file_name.iter().position(|&b| b == 0).unwrap_or(FILE_NAME_SIZE);
+            if &file_name[..len] == name.as_bytes() {
+                return Some((selector, size));
+            }
+        }
+    }
+    None
+}
+
+unsafe fn select(selector: u16) {
+    Port::<u16>::new(SELECTOR_PORT).write(selector);
+}
+
+unsafe fn read(buf: &mut [u8]) {
+    let mut port = Port::<u8>::new(DATA_PORT);
+    for byte in buf.iter_mut() {
+        *byte = port.read();
+    }
+}
+
+// fw_cfg の数値はビッグエンディアン
+unsafe fn read_u32_be() -> u32 {
+    let mut bytes = [0; 4];
+    read(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
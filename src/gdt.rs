@@ -1,8 +1,11 @@
 use x86_64::VirtAddr;
-use x86_64::registers::segmentation::{Segment, CS};
+use x86_64::registers::segmentation::{Segment, CS, SS};
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+use crate::memory::stack;
 
 // IST(interrupt stack table) は TSS(task state segment) の一部
 // IST は、割り込みが発生した場合に使うスタックへのポインタを保持する
@@ -12,52 +15,135 @@ use lazy_static::lazy_static;
 // IST は複数のスタックを持てる
 // ダブルフォルト用に 0 番目のスタックを使うことにする
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// NMI はどの命令の途中でも割り込んでくるので、他のハンドラのスタックを壊さないよう専用のスタックを使う
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+// スタックポインタが壊れていても(ガードページに触っていても)ページフォルトを報告できるようにする
+// ページフォルトのハンドラの中でもう一度ページフォルトが起きると、同じスタックの先頭から使い直すので壊れる
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // 可変なスタティック変数としてスタック領域を確保
-            // ここはスワップアウトされないのか？
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-
-            // スタックは下位アドレスに向けて伸びるので末尾を IST に登録
-            stack_end
-        };
-        tss
-    };
+// IST に登録するスタックと、その名前(スタックがあふれたときの報告に使う)
+const IST_STACKS: [(u16, &str); 4] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault"),
+    (NMI_IST_INDEX, "nmi"),
+    (MACHINE_CHECK_IST_INDEX, "machine check"),
+    (PAGE_FAULT_IST_INDEX, "page fault"),
+];
+
+// 起動直後はまだページを割り当てられないので、静的に確保した領域をスタックに使う
+// memory::install の後で use_guarded_stacks を呼ぶと、ガードページ付きのスタックに差し替える
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+const GUARDED_STACK_SIZE: usize = 4096 * 5;
+
+// 割込みが起きるたびに CPU が読むので、後からスタックを差し替えられるように可変にしておく
+// GDT に登録した後も書き換えるので、参照は作らず生ポインタ(Tss::get)だけで触る
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// 書き換えるのは割込みを止めている間だけ
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    fn get(&self) -> *mut TaskStateSegment {
+        self.0.get()
+    }
+}
+
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+fn init_tss() {
+    // 可変なスタティック変数としてスタック領域を確保
+    // ここはスワップアウトされないのか？
+    static mut IST_STACK: [[u8; BOOT_STACK_SIZE]; IST_STACKS.len()] =
+        [[0; BOOT_STACK_SIZE]; IST_STACKS.len()];
+    // ユーザモード(リング 3)で割込みや例外が起きたときに切り替えるカーネルスタック
+    // syscall の入口でも同じスタックを使う
+    static mut PRIVILEGE_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+    unsafe {
+        let tss = TSS.get();
+        // スタックは下位アドレスに向けて伸びるので末尾を IST に登録
+        for (i, &(index, _)) in IST_STACKS.iter().enumerate() {
+            (*tss).interrupt_stack_table[index as usize] =
+                VirtAddr::from_ptr(addr_of!(IST_STACK[i])) + BOOT_STACK_SIZE;
+        }
+        (*tss).privilege_stack_table[0] =
+            VirtAddr::from_ptr(addr_of!(PRIVILEGE_STACK)) + BOOT_STACK_SIZE;
+    }
+}
+
+// memory::install の後、usermode::init より前に呼ぶ
+// (syscall の入口は usermode::init のときの特権スタックを覚える)
+// 差し替えたスタックがあふれると、ページフォルトのハンドラがどのスタックかを報告する
+pub fn use_guarded_stacks() {
+    for &(index, name) in IST_STACKS.iter() {
+        let top = stack::allocate(name, GUARDED_STACK_SIZE)
+            .expect("failed to allocate an interrupt stack")
+            .leak();
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*TSS.get()).interrupt_stack_table[index as usize] = top;
+        });
+    }
+    let privilege = stack::allocate("privilege", GUARDED_STACK_SIZE)
+        .expect("failed to allocate the privilege stack")
+        .leak();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*TSS.get()).privilege_stack_table[0] = privilege;
+    });
 }
 
 lazy_static! {
     // GDT はユーザ・カーネル空間の切り替えと TSS のロードに使う
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        // syscall/sysret はセレクタを STAR レジスタの値から決め打ちで計算するので、
+        // カーネルのコード・データ、ユーザのデータ・コードの順に並べる必要がある
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        // TSS はスタティック変数なので、ずっと有効
+        let tss_selector = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(TSS.get()) });
+        (gdt, Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        })
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+// リング 3 から入ってきたときに使うカーネルスタックの先頭
+pub fn privilege_stack_top() -> VirtAddr {
+    unsafe { (*TSS.get()).privilege_stack_table[0] }
+}
+
 pub fn init() {
     // use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
     // GDT を更新
+    init_tss();
     GDT.0.load();
 
     unsafe {
         // set_cs(GDT.1.code_selector);
         // コードセグメントと TSS も個別に更新しないといけない
         CS::set_reg(GDT.1.code_selector);
+        // syscall で入ってきたときと同じ SS にそろえておく
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
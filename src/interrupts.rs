@@ -1,19 +1,105 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{println, gdt, hlt_loop};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use crate::apic;
 use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// CPU 例外は 0~31 番を使うので、PIC の割込み番号は 32 番以降にずらす
+// 8259 はプライマリとセカンダリの2つが連結されていて、それぞれ 8 本の IRQ を持つ
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+// PIC はポート経由で設定するので、排他制御のため Mutex で包む
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// ハードウェア割込みの番号
+// IRQ0 がタイマ、IRQ1 がキーボード、IRQ4 がシリアルポート(COM1)につながっている
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+// PIC を再マップし、使う IRQ だけマスクを外す
+// マスクはブートローダ(BIOS)の設定が残っているので、明示的に書き直す
+pub fn init_pics() {
+    use x86_64::instructions::port::Port;
+
+    // IRQ0(タイマ), IRQ1(キーボード), IRQ2(セカンダリとのカスケード), IRQ4(COM1)
+    const PRIMARY_MASK: u8 = !0b0001_0111;
+    const SECONDARY_MASK: u8 = 0xff;
+
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        Port::<u8>::new(0x21).write(PRIMARY_MASK);
+        Port::<u8>::new(0xa1).write(SECONDARY_MASK);
+    }
+}
+
+// 割込みコントローラをどちらで動かすか
+// 起動時に boot_mode で決めて kernel_main から指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptMode {
+    LegacyPic,
+    Apic,
+}
+
+// 起動オプションで指定された割込みコントローラ
+// qemu に -fw_cfg name=opt/blog_os/interrupts,string=pic(または apic)を渡して選ぶ
+// 指定がなければ APIC を選ぶ(対応していない CPU では init_controller が PIC にする)
+pub fn boot_mode() -> InterruptMode {
+    let mut buf = [0; 8];
+    let option = crate::fw_cfg::read_file("opt/blog_os/interrupts", &mut buf)
+        .map(|len| buf[..len].trim_ascii());
+    match option {
+        Some(b"pic") => InterruptMode::LegacyPic,
+        _ => InterruptMode::Apic,
+    }
+}
+
+// blog_os::init で PIC を使い始めた後に呼ぶ
+// APIC を選んでも CPU が対応していなければ PIC のまま動かす
+pub fn init_controller(
+    mode: InterruptMode,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<InterruptMode, MapToError<Size4KiB>> {
+    match mode {
+        InterruptMode::Apic if apic::is_supported() => {
+            apic::init(mapper, frame_allocator)?;
+            Ok(InterruptMode::Apic)
+        }
+        _ => Ok(InterruptMode::LegacyPic),
+    }
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         // x86_64 クレートに IDT 関係の情報が隠蔽されている
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            // ハンドラを登録し、専用のスタックを割当て(スタックの確保自体は gdt.rs で実施)
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        // ページングの有効化はブートローダで実施されている
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // CPU 例外は exception.rs で、全て同じ形式で報告する
+        crate::exception::install(&mut idt);
+
+        // ハードウェア割込みのハンドラは例外の後ろ(32 番以降)に登録する
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_INTERRUPT_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -23,32 +109,66 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
+// 起動してからのタイマ割込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // スレッドが切り替わるとしばらくここに戻ってこないので、先に EOI を送っておく
+    notify_end_of_interrupt(InterruptIndex::Timer);
+    crate::thread::on_timer_tick();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
+extern "x86-interrupt" fn keyboard_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    use x86_64::instructions::port::Port;
+
+    // スキャンコードを読み出さないと、キーボードコントローラが次の割込みを上げてくれない
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::keyboard::add_scancode(scancode);
+
+    notify_end_of_interrupt(InterruptIndex::Keyboard);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode)
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
 {
-    use x86_64::registers::control::Cr2;
+    crate::serial::receive_interrupt();
+    notify_end_of_interrupt(InterruptIndex::Serial);
+}
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+// APIC のスプリアス割込みは本物の割込みではないので EOI を送ってはいけない
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+}
+
+// 割込み処理の終了(EOI)を割込みコントローラに通知しないと、次の割込みが来なくなる
+fn notify_end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
 }
 
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+// NMI のハンドラが専用のスタックで動いて、戻ってこられること
+#[test_case]
+fn test_nmi_handler_returns() {
+    unsafe { core::arch::asm!("int 2") };
+}
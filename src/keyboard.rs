@@ -0,0 +1,495 @@
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::task::Waker;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::{print, println};
+
+// PS/2 キーボードはキーを押す・離すたびに IRQ1 を上げ、ポート 0x60 にスキャンコードを置く
+// 割込みハンドラではスキャンコードをキューに積むだけにして、解釈は読み出す側で行う
+
+// 割込みハンドラ内でもヒープは確保できるが、キーを押すたびに確保しなくて済むよう固定長のキューを事前に確保しておく
+// ArrayQueue はロックを使わないので、割込みハンドラと通常の処理の間で安全に共有できる
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+const SCANCODE_QUEUE_SIZE: usize = 100;
+// 非同期に待っているタスクを割込みハンドラから起こすための Waker
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// ヒープの初期化後に呼ぶ
+pub fn init() {
+    SCANCODE_QUEUE
+        .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+        .expect("keyboard::init should only be called once");
+}
+
+// キーボードの割込みハンドラから呼ばれる
+// ここでブロックしたりヒープを確保したりしてはいけない
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_err() {
+            println!("WARNING: scancode queue full; dropping keyboard input");
+        } else {
+            WAKER.wake();
+        }
+    } else {
+        println!("WARNING: scancode queue uninitialized");
+    }
+}
+
+// キューからスキャンコードを1つ取り出す(なければ None)
+pub fn pop_scancode() -> Option<u8> {
+    SCANCODE_QUEUE.try_get().ok().and_then(|queue| queue.pop().ok())
+}
+
+// 次のスキャンコードが来たときに起こしてもらう
+pub fn register_waker(waker: &Waker) {
+    WAKER.register(waker);
+}
+
+// 登録した Waker が不要になったときに外す
+pub fn take_waker() {
+    WAKER.take();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    // F1~F12
+    Function(u8),
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    // 文字を入力するキー(シフトなしのときの文字で表す)
+    Char(char),
+    // テンキーの数字と記号(NumLock の状態で意味が変わる)
+    Keypad(char),
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Down,
+    Up,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+}
+
+// 修飾キーの状態を適用した後のキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedKey {
+    Unicode(char),
+    RawKey(KeyCode),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+}
+
+// スキャンコードセット 1 (qemu の PS/2 キーボードのデフォルト) のデコーダ
+// 0xe0 が来たら次のバイトは拡張キー(矢印キーや右側の修飾キーなど)
+// 最上位ビットが立っているとキーを離したことを表す
+pub struct ScancodeDecoder {
+    extended: bool,
+    modifiers: Modifiers,
+}
+
+impl ScancodeDecoder {
+    pub const fn new() -> ScancodeDecoder {
+        ScancodeDecoder {
+            extended: false,
+            modifiers: Modifiers {
+                left_shift: false,
+                right_shift: false,
+                left_ctrl: false,
+                right_ctrl: false,
+                left_alt: false,
+                right_alt: false,
+                caps_lock: false,
+                num_lock: true,
+            },
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    // スキャンコードを1バイトずつ渡す
+    // プレフィックスの途中ならまだイベントにならないので None
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if scancode == 0xe0 {
+            self.extended = true;
+            return None;
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        // qemu は拡張キーの前後に偽のシフト(0xe0 0x2a など)を送ることがあるので無視する
+        if extended && matches!(scancode & 0x7f, 0x2a | 0x36) {
+            return None;
+        }
+
+        let state = if scancode & 0x80 != 0 { KeyState::Up } else { KeyState::Down };
+        let code = if extended {
+            extended_key(scancode & 0x7f)
+        } else {
+            normal_key(scancode & 0x7f)
+        };
+
+        let event = KeyEvent { code, state };
+        self.update_modifiers(&event);
+        Some(event)
+    }
+
+    // キーイベントに修飾キーの状態を反映して、文字かそれ以外のキーに変換する
+    // キーを離したイベントと修飾キーそのものは None
+    pub fn process_event(&self, event: KeyEvent) -> Option<DecodedKey> {
+        if event.state == KeyState::Up {
+            return None;
+        }
+
+        let modifiers = &self.modifiers;
+        match event.code {
+            KeyCode::LeftShift | KeyCode::RightShift | KeyCode::LeftCtrl | KeyCode::RightCtrl
+            | KeyCode::LeftAlt | KeyCode::RightAlt | KeyCode::CapsLock | KeyCode::NumLock
+            | KeyCode::ScrollLock => None,
+            KeyCode::Enter => Some(DecodedKey::Unicode('\n')),
+            KeyCode::Tab => Some(DecodedKey::Unicode('\t')),
+            KeyCode::Backspace => Some(DecodedKey::Unicode('\x08')),
+            KeyCode::Escape => Some(DecodedKey::Unicode('\x1b')),
+            KeyCode::Char(c) => {
+                if modifiers.ctrl() && c.is_ascii_alphabetic() {
+                    // Ctrl+A が 0x01 になるような制御文字
+                    return Some(DecodedKey::Unicode((c as u8 - b'a' + 1) as char));
+                }
+                let shifted = if c.is_ascii_alphabetic() {
+                    modifiers.shift() ^ modifiers.caps_lock
+                } else {
+                    modifiers.shift()
+                };
+                Some(DecodedKey::Unicode(if shifted { shift_char(c) } else { c }))
+            }
+            KeyCode::Keypad(c) => {
+                if modifiers.num_lock || (!c.is_ascii_digit() && c != '.') {
+                    Some(DecodedKey::Unicode(c))
+                } else {
+                    keypad_navigation(c).map(DecodedKey::RawKey)
+                }
+            }
+            code => Some(DecodedKey::RawKey(code)),
+        }
+    }
+
+    fn update_modifiers(&mut self, event: &KeyEvent) {
+        let down = event.state == KeyState::Down;
+        let modifiers = &mut self.modifiers;
+        match event.code {
+            KeyCode::LeftShift => modifiers.left_shift = down,
+            KeyCode::RightShift => modifiers.right_shift = down,
+            KeyCode::LeftCtrl => modifiers.left_ctrl = down,
+            KeyCode::RightCtrl => modifiers.right_ctrl = down,
+            KeyCode::LeftAlt => modifiers.left_alt = down,
+            KeyCode::RightAlt => modifiers.right_alt = down,
+            // ロック系のキーは押すたびに切り替える
+            KeyCode::CapsLock if down => modifiers.caps_lock = !modifiers.caps_lock,
+            KeyCode::NumLock if down => modifiers.num_lock = !modifiers.num_lock,
+            _ => {}
+        }
+    }
+}
+
+impl Default for ScancodeDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn normal_key(code: u8) -> KeyCode {
+    // 0x02~0x0d: 数字キーの列
+    const ROW_NUMBER: &[u8] = b"1234567890-=";
+    // 0x10~0x1b: QWERTY の列
+    const ROW_TOP: &[u8] = b"qwertyuiop[]";
+    // 0x1e~0x29: ASDF の列
+    const ROW_HOME: &[u8] = b"asdfghjkl;'`";
+    // 0x2b~0x35: ZXCV の列
+    const ROW_BOTTOM: &[u8] = b"\\zxcvbnm,./";
+
+    match code {
+        0x01 => KeyCode::Escape,
+        0x02..=0x0d => KeyCode::Char(ROW_NUMBER[usize::from(code - 0x02)] as char),
+        0x0e => KeyCode::Backspace,
+        0x0f => KeyCode::Tab,
+        0x10..=0x1b => KeyCode::Char(ROW_TOP[usize::from(code - 0x10)] as char),
+        0x1c => KeyCode::Enter,
+        0x1d => KeyCode::LeftCtrl,
+        0x1e..=0x29 => KeyCode::Char(ROW_HOME[usize::from(code - 0x1e)] as char),
+        0x2a => KeyCode::LeftShift,
+        0x2b..=0x35 => KeyCode::Char(ROW_BOTTOM[usize::from(code - 0x2b)] as char),
+        0x36 => KeyCode::RightShift,
+        0x37 => KeyCode::Keypad('*'),
+        0x38 => KeyCode::LeftAlt,
+        0x39 => KeyCode::Char(' '),
+        0x3a => KeyCode::CapsLock,
+        0x3b..=0x44 => KeyCode::Function(code - 0x3b + 1),
+        0x45 => KeyCode::NumLock,
+        0x46 => KeyCode::ScrollLock,
+        0x47 => KeyCode::Keypad('7'),
+        0x48 => KeyCode::Keypad('8'),
+        0x49 => KeyCode::Keypad('9'),
+        0x4a => KeyCode::Keypad('-'),
+        0x4b => KeyCode::Keypad('4'),
+        0x4c => KeyCode::Keypad('5'),
+        0x4d => KeyCode::Keypad('6'),
+        0x4e => KeyCode::Keypad('+'),
+        0x4f => KeyCode::Keypad('1'),
+        0x50 => KeyCode::Keypad('2'),
+        0x51 => KeyCode::Keypad('3'),
+        0x52 => KeyCode::Keypad('0'),
+        0x53 => KeyCode::Keypad('.'),
+        0x57 => KeyCode::Function(11),
+        0x58 => KeyCode::Function(12),
+        code => KeyCode::Unknown(code),
+    }
+}
+
+fn extended_key(code: u8) -> KeyCode {
+    match code {
+        0x1c => KeyCode::Enter,
+        0x1d => KeyCode::RightCtrl,
+        0x35 => KeyCode::Keypad('/'),
+        0x38 => KeyCode::RightAlt,
+        0x47 => KeyCode::Home,
+        0x48 => KeyCode::ArrowUp,
+        0x49 => KeyCode::PageUp,
+        0x4b => KeyCode::ArrowLeft,
+        0x4d => KeyCode::ArrowRight,
+        0x4f => KeyCode::End,
+        0x50 => KeyCode::ArrowDown,
+        0x51 => KeyCode::PageDown,
+        0x52 => KeyCode::Insert,
+        0x53 => KeyCode::Delete,
+        code => KeyCode::Unknown(0x80 | code),
+    }
+}
+
+// NumLock が切れているときのテンキーは矢印キーなどとして扱う
+fn keypad_navigation(c: char) -> Option<KeyCode> {
+    match c {
+        '7' => Some(KeyCode::Home),
+        '8' => Some(KeyCode::ArrowUp),
+        '9' => Some(KeyCode::PageUp),
+        '4' => Some(KeyCode::ArrowLeft),
+        '6' => Some(KeyCode::ArrowRight),
+        '1' => Some(KeyCode::End),
+        '2' => Some(KeyCode::ArrowDown),
+        '3' => Some(KeyCode::PageDown),
+        '0' => Some(KeyCode::Insert),
+        '.' => Some(KeyCode::Delete),
+        _ => None,
+    }
+}
+
+// US 配列でシフトを押したときの文字
+fn shift_char(c: char) -> char {
+    match c {
+        'a'..='z' => c.to_ascii_uppercase(),
+        '1' => '!',
+        '2' => '@',
+        '3' => '#',
+        '4' => '$',
+        '5' => '%',
+        '6' => '^',
+        '7' => '&',
+        '8' => '*',
+        '9' => '(',
+        '0' => ')',
+        '-' => '_',
+        '=' => '+',
+        '[' => '{',
+        ']' => '}',
+        ';' => ':',
+        '\'' => '"',
+        '`' => '~',
+        '\\' => '|',
+        ',' => '<',
+        '.' => '>',
+        '/' => '?',
+        c => c,
+    }
+}
+
+lazy_static! {
+    // デコーダは修飾キーの状態を持つので、読み出す側で1つを共有する
+    static ref DECODER: Mutex<ScancodeDecoder> = Mutex::new(ScancodeDecoder::new());
+}
+
+// キューにスキャンコードがあればデコードして返す(ブロックしない)
+pub fn try_read_key() -> Option<DecodedKey> {
+    let mut decoder = DECODER.lock();
+    while let Some(scancode) = pop_scancode() {
+        if let Some(event) = decoder.add_byte(scancode) {
+            if let Some(key) = decoder.process_event(event) {
+                return Some(key);
+            }
+        }
+    }
+    None
+}
+
+// キーが押されるまで hlt で待つ
+pub fn read_key() -> DecodedKey {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        if let Some(key) = try_read_key() {
+            return key;
+        }
+        // キューが空なのを確認してから hlt するまでの間に割込みが来ると取りこぼすので、
+        // 割込みを止めて確認し、hlt と同時に割込みを再開する
+        interrupts::disable();
+        if scancode_queue_is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+fn scancode_queue_is_empty() -> bool {
+    SCANCODE_QUEUE.try_get().map_or(true, |queue| queue.is_empty())
+}
+
+// Enter が押されるまでの入力を1行読む(改行は含まない)
+// キーボードとシリアルのどちらからでも受け付け、キーボードの入力は VGA に、シリアルの入力はシリアルにエコーする
+pub fn read_line() -> String {
+    use x86_64::instructions::interrupts;
+
+    let mut line = String::new();
+    loop {
+        if let Some(key) = try_read_key() {
+            match key {
+                DecodedKey::Unicode('\n') => {
+                    println!();
+                    return line;
+                }
+                DecodedKey::Unicode('\x08') => {
+                    if line.pop().is_some() {
+                        print!("\x08");
+                    }
+                }
+                DecodedKey::Unicode(c) if c.is_ascii() && !c.is_ascii_control() => {
+                    line.push(c);
+                    print!("{}", c);
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        if let Some(byte) = crate::serial::try_read_byte() {
+            match byte {
+                // 端末からの Enter は CR で届く
+                b'\r' | b'\n' => {
+                    crate::serial_println!();
+                    return line;
+                }
+                // Backspace は端末によって BS か DEL のどちらか
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        crate::serial_print!("\x08 \x08");
+                    }
+                }
+                0x20..=0x7e => {
+                    line.push(byte as char);
+                    crate::serial_print!("{}", byte as char);
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        // read_key と同じく、両方のキューが空なのを割込みを止めて確かめてから hlt する
+        // serial::init の前はシリアルの受信割込みがないので、次の割込み(少なくともタイマ)で起きて確認し直す
+        interrupts::disable();
+        if scancode_queue_is_empty() && !crate::serial::has_received() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_decode_plain_key() {
+    let mut decoder = ScancodeDecoder::new();
+    let event = decoder.add_byte(0x1e).expect("no event for 'a' press");
+    assert_eq!(event, KeyEvent { code: KeyCode::Char('a'), state: KeyState::Down });
+    assert_eq!(decoder.process_event(event), Some(DecodedKey::Unicode('a')));
+}
+
+#[test_case]
+fn test_decode_shifted_key() {
+    let mut decoder = ScancodeDecoder::new();
+    decoder.add_byte(0x2a);
+    let event = decoder.add_byte(0x02).expect("no event for '1' press");
+    assert_eq!(decoder.process_event(event), Some(DecodedKey::Unicode('!')));
+    decoder.add_byte(0xaa);
+    assert!(!decoder.modifiers().shift());
+}
+
+#[test_case]
+fn test_decode_extended_key() {
+    let mut decoder = ScancodeDecoder::new();
+    assert_eq!(decoder.add_byte(0xe0), None);
+    let event = decoder.add_byte(0x48).expect("no event for arrow press");
+    assert_eq!(decoder.process_event(event), Some(DecodedKey::RawKey(KeyCode::ArrowUp)));
+    assert_eq!(decoder.add_byte(0xe0), None);
+    let event = decoder.add_byte(0xc8).expect("no event for arrow release");
+    assert_eq!(event.state, KeyState::Up);
+}
@@ -11,16 +11,29 @@
 
 extern crate alloc;
 
+use bootloader::BootInfo;
 #[cfg(test)]
-use bootloader::{BootInfo, entry_point};
+use bootloader::entry_point;
+use core::fmt;
 use core::panic::PanicInfo;
 
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
+pub mod exception;
+pub mod backtrace;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod apic;
+pub mod fw_cfg;
+pub mod keyboard;
+pub mod shell;
+pub mod task;
+pub mod thread;
+pub mod syscall;
+pub mod usermode;
+pub mod elf;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -72,21 +85,81 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
     loop {}
 }
 
+// ヒープを使わずに文字列を組み立てるバッファ(panic のメッセージを調べるテストなどで使う)
+// 入りきらない分は捨てる
+pub struct FmtBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl FmtBuffer {
+    pub fn format(args: fmt::Arguments) -> FmtBuffer {
+        let mut buffer = FmtBuffer { bytes: [0; 256], len: 0 };
+        let _ = fmt::Write::write_fmt(&mut buffer, args);
+        buffer
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len];
+        // 文字の途中で切れていたら、そこまでにする
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl fmt::Write for FmtBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// 結合テストのメモリの初期化(main.rs と同じ順番)
+// ヒープを作ってからバディアロケータに切り替え、ページテーブルとフレームアロケータを memory::install で共有する
+pub fn test_init_memory(boot_info: &'static BootInfo) {
+    use memory::BootInfoFrameAllocatior;
+
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocatior::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    let frame_allocator = frame_allocator.into_buddy()
+        .expect("no room for the buddy allocator metadata");
+    memory::install(mapper, frame_allocator);
+}
+
 pub fn init() {
+    // これ以降にマップするデータのページには NO_EXECUTE を付ける
+    memory::protection::enable_nx_and_write_protect();
     gdt::init();
     interrupts::init_idt();
+    // PIC を初期化してからハードウェア割込みを有効にする
+    interrupts::init_pics();
+    x86_64::instructions::interrupts::enable();
 }
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    // バックトレースがページテーブルを読めるように、物理メモリのマッピングの場所を教えておく
+    memory::set_physical_memory_offset(phys_mem_offset);
+    backtrace::init(&boot_info.memory_map, phys_mem_offset);
     test_main();
     loop {}
 }
@@ -27,12 +27,40 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // パニックしたときに関数名で呼び出し履歴を表示できるよう、カーネルのシンボルテーブルを探しておく
+    blog_os::backtrace::init(&boot_info.memory_map, phys_mem_offset);
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocatior::init(&boot_info.memory_map)
+        BootInfoFrameAllocatior::init(&boot_info.memory_map, phys_mem_offset)
     };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    // キーボードとシリアルの受信キューはヒープに確保するので、ヒープの後で初期化する
+    blog_os::keyboard::init();
+    blog_os::serial::init();
+
+    // 割込みコントローラは起動オプションで選ぶ
+    // APIC に対応していない CPU では自動的に PIC にフォールバックする
+    let interrupt_mode = blog_os::interrupts::init_controller(
+        blog_os::interrupts::boot_mode(), &mut mapper, &mut frame_allocator
+    ).expect("interrupt controller initialization failed");
+    println!("interrupt controller: {:?}", interrupt_mode);
+
+    // 起動時の割り当てが済んだら、連続したフレームも割り当てられるバディアロケータに切り替える
+    let frame_allocator = frame_allocator.into_buddy()
+        .expect("no room for the buddy allocator metadata");
+    // ここから先はヒープが足りなくなると、共有したページテーブルとフレームアロケータで広げる
+    memory::install(mapper, frame_allocator);
+    // カーネルのセグメントを ELF の権限どおりに張り直し、書き込めるページを実行不可にする
+    memory::protection::protect_kernel(&boot_info.memory_map)
+        .expect("failed to protect the kernel mappings");
+    // 例外用と特権レベル切り替え用のスタックを、ガードページ付きのものに差し替える
+    blog_os::gdt::use_guarded_stacks();
+    blog_os::usermode::init(phys_mem_offset);
+    // ここから kernel_main 自身も1つのスレッドとしてタイマで切り替えられる
+    blog_os::thread::init();
+    // 起動時のマッピングが揃ったので、書き込めて実行もできるページが残っていないか確かめる
+    memory::protection::check();
 
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
@@ -66,13 +94,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     println!("It did not crash!");
 
-    blog_os::hlt_loop();
+    let mut shell = blog_os::shell::Shell::new(&boot_info.memory_map);
+    shell.run();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    blog_os::backtrace::print();
     loop {}
 }
 
@@ -0,0 +1,214 @@
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+};
+
+// バディアロケータ
+// 2^order 個の連続したフレーム(ブロック)を単位に割り当て、解放するときは隣(バディ)も空いていれば
+// 1つ大きなブロックにまとめ直す
+// ブロックは物理アドレス 0 から自分の大きさ単位でそろっているので、order 9 なら 2MiB、
+// order 18 なら 1GiB のページにそのまま使える
+//
+// ヒープは使わない(ヒープのページを用意するのにも使いたい)
+// 空きブロックのリストは空きブロック自身の中に書き、各フレームの状態は物理メモリから切り出したバイト列に持つ
+
+pub const MAX_ORDER: usize = 18;
+pub const ORDER_2MIB: usize = 9;
+pub const ORDER_1GIB: usize = 18;
+
+const FRAME_SIZE: u64 = 4096;
+// リストの終端
+const NULL: u64 = u64::MAX;
+// order_map の値: 空きブロックの先頭なら FREE | order、それ以外は 0
+const FREE: u8 = 0x80;
+
+// 空きブロックの先頭に書く双方向リストのノード
+// バディをまとめるときにリストの途中から外すので双方向にする
+struct FreeNode {
+    next: u64,
+    prev: u64,
+}
+
+pub struct BuddyAllocator {
+    free_lists: [u64; MAX_ORDER + 1],
+    // フレーム番号ごとの状態
+    order_map: &'static mut [u8],
+    free_frames: u64,
+    physical_memory_offset: VirtAddr,
+}
+
+impl BuddyAllocator {
+    // frame_count 個のフレーム(物理アドレス 0 から)を管理できる空のアロケータを作る
+    // 状態を持つバイト列は metadata から始まる物理メモリに置く
+    /// # Safety
+    /// metadata から metadata_frames(frame_count) 個のフレームが他で使われておらず、アロケータより長く生きること
+    /// physical_memory_offset から物理メモリ全体がマップされていること
+    pub unsafe fn new(
+        frame_count: u64,
+        metadata: PhysAddr,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let map_ptr = (physical_memory_offset + metadata.as_u64()).as_mut_ptr::<u8>();
+        let order_map = core::slice::from_raw_parts_mut(map_ptr, frame_count as usize);
+        order_map.fill(0);
+        BuddyAllocator {
+            free_lists: [NULL; MAX_ORDER + 1],
+            order_map,
+            free_frames: 0,
+            physical_memory_offset,
+        }
+    }
+
+    // new に渡す metadata に必要なフレーム数
+    pub fn metadata_frames(frame_count: u64) -> u64 {
+        frame_count.div_ceil(FRAME_SIZE)
+    }
+
+    // [start, end) の空いているフレームをアロケータに渡す
+    /// # Safety
+    /// [start, end) のフレームが他で使われておらず、new に渡した metadata とも重ならないこと
+    /// 同じフレームを2回渡してはいけない(空きリストがフレーム自身に書き込まれるので壊れる)
+    pub unsafe fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.align_up(FRAME_SIZE).as_u64();
+        let limit = self.order_map.len() as u64 * FRAME_SIZE;
+        let end = end.align_down(FRAME_SIZE).as_u64().min(limit);
+        while addr < end {
+            // addr にそろっていて、end をはみ出さない一番大きなブロックにする
+            let mut order = 0;
+            while order < MAX_ORDER
+                && addr.is_multiple_of(block_size(order + 1))
+                && addr + block_size(order + 1) <= end
+            {
+                order += 1;
+            }
+            self.deallocate(PhysAddr::new(addr), order);
+            addr += block_size(order);
+        }
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    // size バイトを収めるのに必要な order
+    pub fn order_for_size(size: u64) -> usize {
+        let frames = size.max(1).div_ceil(FRAME_SIZE);
+        frames.next_power_of_two().trailing_zeros() as usize
+    }
+
+    // 2^order 個の連続したフレームを割り当てる
+    // 先頭アドレスは 2^order フレーム単位でそろっている
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // 空きのある一番小さいブロックを探し、大きすぎれば半分に割っていく
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NULL)?;
+        let block = self.free_lists[current];
+        unsafe { self.remove(block, current) };
+        while current > order {
+            current -= 1;
+            unsafe { self.push(block + block_size(current), current) };
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysAddr::new(block))
+    }
+
+    // allocate で割り当てたブロックを返す
+    // 大きなブロックを分けて使ったとき(大きなページを分けたときなど)は、一部だけ小さい order で返してもよい
+    // 空きの状態はフレームごとに持っているので、残りが返ってきたところでまとめ直される
+    /// # Safety
+    /// addr から 2^order 個のフレームは、このアロケータの allocate が返したブロック(の一部)で、
+    /// まだ返していないこと。addr は 2^order フレーム単位でそろっていること
+    /// 返した後はそのフレームを使ってはいけない(空きリストの管理に使われる)
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let mut block = addr.as_u64();
+        let mut order = order;
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = block ^ block_size(order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    fn is_free_block(&self, addr: u64, order: usize) -> bool {
+        let index = (addr / FRAME_SIZE) as usize;
+        index < self.order_map.len() && self.order_map[index] == FREE | order as u8
+    }
+
+    fn node(&self, addr: u64) -> *mut FreeNode {
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, addr: u64, order: usize) {
+        let head = self.free_lists[order];
+        self.node(addr).write(FreeNode { next: head, prev: NULL });
+        if head != NULL {
+            (*self.node(head)).prev = addr;
+        }
+        self.free_lists[order] = addr;
+        self.order_map[(addr / FRAME_SIZE) as usize] = FREE | order as u8;
+    }
+
+    unsafe fn remove(&mut self, addr: u64, order: usize) {
+        let FreeNode { next, prev } = self.node(addr).read();
+        if prev == NULL {
+            self.free_lists[order] = next;
+        } else {
+            (*self.node(prev)).next = next;
+        }
+        if next != NULL {
+            (*self.node(next)).prev = prev;
+        }
+        self.order_map[(addr / FRAME_SIZE) as usize] = 0;
+    }
+}
+
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0).map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(ORDER_2MIB).map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate(ORDER_1GIB).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame.start_address(), 0);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(frame.start_address(), ORDER_2MIB);
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate(frame.start_address(), ORDER_1GIB);
+    }
+}
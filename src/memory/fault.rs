@@ -0,0 +1,137 @@
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+// ページフォルトを解決するハンドラ(リゾルバ)の登録
+// 仮想アドレスの範囲ごとにリゾルバを登録しておくと、その範囲でページフォルトが起きたときに呼ばれる
+// リゾルバがページをマップして true を返せば、フォルトした命令からやり直す
+// どのリゾルバも解決できなかったフォルトだけが致命的になる
+//
+// リゾルバは例外ハンドラの中(割込み禁止、ページフォルト用のスタック)で呼ばれるので、
+// ロックは try_lock で取り、取れなければ false を返すこと
+//
+// カーネル自身はまだ範囲を登録していない
+// ヒープとカーネルスタックは MAPPER を持ったまま触ることがあり、そこでフォルトすると
+// demand_zero はロックを取れずに解決できないので、どちらも前もってマップしている
+// (ヒープは広げるときに大きなページでまとめてマップし、スタックはガード以外を全部マップする)
+
+const MAX_REGIONS: usize = 16;
+
+// フォルトしたアドレスとエラーコードを受け取り、解決できたら true を返す
+pub type FaultResolver = fn(VirtAddr, PageFaultErrorCode) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultRegionError {
+    // 登録済みの範囲と重なっている
+    Overlap,
+    TooManyRegions,
+}
+
+#[derive(Clone, Copy)]
+struct FaultRegion {
+    name: &'static str,
+    start: VirtAddr,
+    end: VirtAddr,
+    resolver: FaultResolver,
+}
+
+// 例外ハンドラからも引くので、ロックを持つ間は割込みを止めておく
+static REGIONS: Mutex<[Option<FaultRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+// [start, start + size) で起きたページフォルトを resolver に任せる
+pub fn register(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    resolver: FaultResolver,
+) -> Result<(), FaultRegionError> {
+    let end = start + size;
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions.iter().flatten().any(|region| start < region.end && region.start < end) {
+            return Err(FaultRegionError::Overlap);
+        }
+        let slot = regions.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(FaultRegionError::TooManyRegions)?;
+        *slot = Some(FaultRegion { name, start, end, resolver });
+        Ok(())
+    })
+}
+
+// start から始まる範囲の登録を外す
+// すでにマップされたページはそのまま残る
+pub fn unregister(start: VirtAddr) {
+    interrupts::without_interrupts(|| {
+        for slot in REGIONS.lock().iter_mut() {
+            if matches!(slot, Some(region) if region.start == start) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+// addr を含む範囲の名前
+pub fn region_name(addr: VirtAddr) -> Option<&'static str> {
+    find(addr).map(|region| region.name)
+}
+
+// ページフォルトのハンドラから呼ばれる
+pub(crate) fn resolve(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // リゾルバの中でも登録を読めるよう、呼び出す前にロックを外す
+    match find(addr) {
+        Some(region) => (region.resolver)(addr, error_code),
+        None => false,
+    }
+}
+
+fn find(addr: VirtAddr) -> Option<FaultRegion> {
+    let regions = REGIONS.try_lock()?;
+    regions.iter()
+        .flatten()
+        .find(|region| region.start <= addr && addr < region.end)
+        .copied()
+}
+
+// 触ったときに初めて、0 で埋めたフレームをマップするリゾルバ
+// カーネルからの、まだマップされていないページへのアクセスだけを解決する
+pub fn demand_zero(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.intersects(
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE
+    ) {
+        return false;
+    }
+
+    let (mut mapper, mut frame_allocator) = match (super::MAPPER.try_lock(), super::FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let frame: PhysFrame<Size4KiB> = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let frame_ptr: *mut u8 = (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
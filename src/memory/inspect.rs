@@ -0,0 +1,207 @@
+use core::fmt;
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PageTableIndex, PhysFrame},
+};
+
+// 有効なページテーブル(CR3 が指す L4 テーブル)を読んで、マッピングを調べる
+// x86_64 クレートの Translate と違い、どの段のエントリで決まったかと、全段を合わせた権限も返す
+// テーブルは物理メモリのマッピング経由で読むので、memory::init の後で使う
+
+// 変換を決めたエントリの大きさ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 4096,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+            PageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+
+    // level 段目のテーブルのエントリがページを指しているときの大きさ
+    pub(crate) fn at_level(level: u8) -> Self {
+        match level {
+            1 => PageSize::Size4KiB,
+            2 => PageSize::Size2MiB,
+            _ => PageSize::Size1GiB,
+        }
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PageSize::Size4KiB => "4KiB",
+            PageSize::Size2MiB => "2MiB",
+            PageSize::Size1GiB => "1GiB",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    pub page_size: PageSize,
+    // 変換を決めたエントリがあったテーブルの段(4KiB ページなら 1、2MiB なら 2、1GiB なら 3)
+    pub level: u8,
+    // 全段のエントリを合わせた実際の権限
+    pub flags: PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotMapped {
+    // PRESENT でないエントリがあったテーブルの段
+    pub level: u8,
+}
+
+// 仮想・物理アドレスが続いていて、ページサイズと権限が同じページをまとめたもの
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys_start: PhysAddr,
+    pub page_size: PageSize,
+    // PERMISSION_FLAGS の部分だけ(ACCESSED や DIRTY はページごとに違うので落とす)
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    // 範囲の最後のバイトのアドレス(アドレス空間の末尾まで続く範囲もあるので、末尾の次は使わない)
+    pub fn last(&self) -> VirtAddr {
+        self.start + (self.size - 1)
+    }
+
+    fn extends_to(&self, next: &MappedRange) -> bool {
+        self.page_size == next.page_size
+            && self.flags == next.flags
+            && self.start.as_u64().checked_add(self.size) == Some(next.start.as_u64())
+            && self.phys_start.as_u64() + self.size == next.phys_start.as_u64()
+    }
+}
+
+const PERMISSION_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::GLOBAL)
+    .union(PageTableFlags::NO_EXECUTE);
+
+// addr の変換を、有効なページテーブルをたどって調べる
+pub fn translate(addr: VirtAddr) -> Result<Translation, NotMapped> {
+    let mut frame = Cr3::read().0;
+    let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut level = 4;
+    loop {
+        let entry = &table(frame)[index(addr, level)];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(NotMapped { level });
+        }
+        flags = combine(flags, entry.flags());
+        if is_leaf(level, entry.flags()) {
+            let page_size = PageSize::at_level(level);
+            let offset = addr.as_u64() & (page_size.bytes() - 1);
+            return Ok(Translation { phys: entry.addr() + offset, page_size, level, flags });
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+        level -= 1;
+    }
+}
+
+// 有効なページテーブルにある全てのマッピングを、仮想アドレスの順にまとめて f に渡す
+pub fn for_each_range(mut f: impl FnMut(&MappedRange)) {
+    let mut current: Option<MappedRange> = None;
+    let initial = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk(Cr3::read().0, 4, 0, initial, &mut |page| match current.as_mut() {
+        Some(range) if range.extends_to(&page) => range.size += page.size,
+        _ => {
+            if let Some(range) = current.replace(page) {
+                f(&range);
+            }
+        }
+    });
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+// r/w/x/u の形で権限を表示する(u はユーザモードから触れる)
+pub struct Permissions(pub PageTableFlags);
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.0.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "r{}{}{}",
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.0.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+        )
+    }
+}
+
+// level 段目のテーブルを先頭から見て、ページを指すエントリを f に渡す
+// base はこのテーブルが受け持つ仮想アドレスの先頭(符号拡張する前)
+fn walk(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    upper: PageTableFlags,
+    f: &mut dyn FnMut(MappedRange),
+) {
+    for (i, entry) in table(frame).iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base | (i as u64) << (12 + 9 * (u32::from(level) - 1));
+        let flags = combine(upper, entry.flags());
+        if is_leaf(level, entry.flags()) {
+            let page_size = PageSize::at_level(level);
+            f(MappedRange {
+                start: VirtAddr::new_truncate(start),
+                size: page_size.bytes(),
+                phys_start: entry.addr(),
+                page_size,
+                flags: flags & PERMISSION_FLAGS,
+            });
+        } else {
+            walk(PhysFrame::containing_address(entry.addr()), level - 1, start, flags, f);
+        }
+    }
+}
+
+// 上の段までの権限とエントリのフラグを合わせる
+// WRITABLE と USER_ACCESSIBLE はどの段にも付いているときだけ、NO_EXECUTE はどこかの段に付いていれば効く
+// それ以外のフラグは一番下の段のものを使う
+fn combine(upper: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restrictive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry - restrictive) | (entry & upper & restrictive) | (upper & PageTableFlags::NO_EXECUTE)
+}
+
+// L3 と L2 のエントリは HUGE_PAGE が付いていればページを指す(L1 のその位置のビットは PAT)
+fn is_leaf(level: u8, flags: PageTableFlags) -> bool {
+    level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE))
+}
+
+fn index(addr: VirtAddr, level: u8) -> PageTableIndex {
+    match level {
+        4 => addr.p4_index(),
+        3 => addr.p3_index(),
+        2 => addr.p2_index(),
+        _ => addr.p1_index(),
+    }
+}
+
+fn table(frame: PhysFrame) -> &'static PageTable {
+    unsafe { &*super::table_ptr(frame, super::physical_memory_offset()) }
+}
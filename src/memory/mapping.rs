@@ -0,0 +1,312 @@
+use spin::Once;
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    instructions::tlb,
+    structures::paging::{
+        mapper::MapToError, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        FrameAllocator, Size1GiB, Size2MiB, Size4KiB,
+    },
+    structures::paging::page_table::PageTableEntry,
+};
+use super::buddy::{BuddyAllocator, ORDER_1GIB, ORDER_2MIB};
+use super::inspect::{NotMapped, PageSize};
+
+// 大きなページ(2MiB、1GiB)を使えるところでは使うマッピング
+// 仮想アドレスと物理アドレスの両方がそろっていて、残りの大きさが足りるところだけ大きなページにし、
+// それ以外は 4KiB のページでマップする
+// 一部だけ権限を変えたいときは、大きなページを 512 個の小さなページに分けてから変える
+//
+// x86_64 クレートの Mapper は大きなページを分けられないので、protect はテーブルを直接書き換える
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectError {
+    NotMapped(VirtAddr),
+    // 大きなページを分けるためのテーブルを確保できなかった
+    FrameAllocationFailed,
+}
+
+// 1GiB のページに対応している CPU か(CPUID 0x8000_0001 の EDX の 26 ビット目)
+// 仮想マシンでは CPUID が重いので、一度だけ調べて覚えておく
+#[allow(unused_unsafe)]
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    static SUPPORTED: Once<bool> = Once::new();
+    *SUPPORTED.call_once(|| {
+        let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+        max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+    })
+}
+
+// [start, start + size) を phys から始まる物理メモリにマップする(MMIO や物理メモリの窓)
+/// # Safety
+/// phys から size バイトの物理メモリを、このマッピング経由で読み書きしてよいこと
+/// (フレームアロケータが管理しているフレームやページテーブルを書き換えられるように張ってはいけない)
+pub unsafe fn map_range(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let size = size.next_multiple_of(PageSize::Size4KiB.bytes());
+    let mut offset = 0;
+    while offset < size {
+        let page_size = page_sizes()
+            .find(|page_size| fits(*page_size, start + offset, phys + offset, size - offset))
+            .unwrap_or(PageSize::Size4KiB);
+        map_page(mapper, start + offset, phys + offset, page_size, flags, frame_allocator)?;
+        offset += page_size.bytes();
+    }
+    Ok(())
+}
+
+// [start, start + size) に新しいフレームを割り当ててマップし、マップできた大きさを返す
+// 大きなページのフレームが取れなければ小さいページにする
+// 物理メモリが足りなくなったらそこまでにして、1ページもマップできなければエラーにする
+pub fn map_anonymous(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BuddyAllocator,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let size = size.next_multiple_of(PageSize::Size4KiB.bytes());
+    let mut mapped = 0;
+    while mapped < size {
+        match map_anonymous_page(mapper, start + mapped, size - mapped, flags, frame_allocator) {
+            Ok(page_size) => mapped += page_size.bytes(),
+            Err(error) if mapped == 0 => return Err(error),
+            Err(_) => break,
+        }
+    }
+    Ok(mapped)
+}
+
+// [start, start + size) の全てのページの権限を flags にする
+// 範囲が大きなページの一部にしかかかっていなければ、そのページを分けてから変える
+// 書き込みやユーザからのアクセスを許すときは、上の段のエントリにも許可を足す
+pub fn protect(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ProtectError> {
+    let start = start.align_down(PageSize::Size4KiB.bytes());
+    let end = (start + size).align_up(PageSize::Size4KiB.bytes());
+    let physical_memory_offset = mapper.phys_offset();
+    let mut addr = start;
+    while addr < end {
+        let (entry, level) = leaf_entry(mapper, addr, flags & PARENT_FLAGS)
+            .map_err(|_| ProtectError::NotMapped(addr))?;
+        let page_size = PageSize::at_level(level);
+        let page_start = addr.align_down(page_size.bytes());
+        if page_start < start || end - page_start < page_size.bytes() {
+            // 一部だけ変えるので、分けてからもう一度引き直す
+            split(entry, level, physical_memory_offset, frame_allocator)?;
+            tlb::flush(page_start);
+            continue;
+        }
+        let huge = entry.flags() & PageTableFlags::HUGE_PAGE;
+        entry.set_flags(flags | PageTableFlags::PRESENT | huge);
+        tlb::flush(page_start);
+        addr = page_start + page_size.bytes();
+    }
+    Ok(())
+}
+
+// [start, start + size) のページのマップを外す(マップされていないところは飛ばす)
+// free_frames なら外したページのフレームをフレームアロケータに返す
+// (MMIO のようにデバイスの物理アドレスをマップしていたときは返さない)
+// 範囲が大きなページの一部にしかかかっていなければ、そのページを分けてから外す
+pub fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    frame_allocator: &mut BuddyAllocator,
+    free_frames: bool,
+) -> Result<(), ProtectError> {
+    let start = start.align_down(PageSize::Size4KiB.bytes());
+    let end = (start + size).align_up(PageSize::Size4KiB.bytes());
+    let physical_memory_offset = mapper.phys_offset();
+    let mut addr = start;
+    while addr < end {
+        let (entry, level) = match leaf_entry(mapper, addr, PageTableFlags::empty()) {
+            Ok(leaf) => leaf,
+            Err(NotMapped { level }) => {
+                // その段のエントリが受け持つ範囲には、1ページもマップされていない
+                // (飛ばした先が範囲の外なら、正規のアドレスでないこともあるので先に比べる)
+                let covered = 1u64 << (12 + 9 * (u32::from(level) - 1));
+                let next = (addr.as_u64() & !(covered - 1)) + covered;
+                if next >= end.as_u64() {
+                    break;
+                }
+                addr = VirtAddr::new(next);
+                continue;
+            }
+        };
+        let page_size = PageSize::at_level(level);
+        let page_start = addr.align_down(page_size.bytes());
+        if page_start < start || end - page_start < page_size.bytes() {
+            // 一部だけ外すので、分けてからもう一度引き直す
+            split(entry, level, physical_memory_offset, frame_allocator)?;
+            tlb::flush(page_start);
+            continue;
+        }
+        let frame = entry.addr();
+        entry.set_unused();
+        tlb::flush(page_start);
+        if free_frames {
+            // 分けた大きなページでは、割り当てたブロックの一部だけを返すことになる
+            unsafe { frame_allocator.deallocate(frame, order(page_size)) };
+        }
+        addr = page_start + page_size.bytes();
+    }
+    Ok(())
+}
+
+// 大きい順に、使えるページの大きさ
+fn page_sizes() -> impl Iterator<Item = PageSize> {
+    let gib = if supports_1gib_pages() { Some(PageSize::Size1GiB) } else { None };
+    gib.into_iter().chain([PageSize::Size2MiB, PageSize::Size4KiB].iter().copied())
+}
+
+// virt と phys が page_size にそろっていて、残り remaining に収まるか
+fn fits(page_size: PageSize, virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+    virt.is_aligned(page_size.bytes())
+        && phys.is_aligned(page_size.bytes())
+        && remaining >= page_size.bytes()
+}
+
+fn map_anonymous_page(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    remaining: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BuddyAllocator,
+) -> Result<PageSize, MapToError<Size4KiB>> {
+    for page_size in page_sizes() {
+        // 物理アドレスはバディアロケータがブロックの大きさにそろえてくれる
+        if !virt.is_aligned(page_size.bytes()) || remaining < page_size.bytes() {
+            continue;
+        }
+        let phys = match frame_allocator.allocate(order(page_size)) {
+            Some(phys) => phys,
+            None => continue,
+        };
+        return match unsafe { map_page(mapper, virt, phys, page_size, flags, frame_allocator) } {
+            Ok(()) => Ok(page_size),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate(phys, order(page_size)) };
+                Err(error)
+            }
+        };
+    }
+    Err(MapToError::FrameAllocationFailed)
+}
+
+fn order(page_size: PageSize) -> usize {
+    match page_size {
+        PageSize::Size4KiB => 0,
+        PageSize::Size2MiB => ORDER_2MIB,
+        PageSize::Size1GiB => ORDER_1GIB,
+    }
+}
+
+unsafe fn map_page(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    page_size: PageSize,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    // 途中の段のテーブルは map_to が作る(そのためのフレームは 4KiB)
+    match page_size {
+        PageSize::Size4KiB => mapper
+            .map_to(Page::<Size4KiB>::containing_address(virt), PhysFrame::containing_address(phys), flags, frame_allocator)
+            .map(|flush| flush.flush()),
+        PageSize::Size2MiB => mapper
+            .map_to(Page::<Size2MiB>::containing_address(virt), PhysFrame::containing_address(phys), flags, frame_allocator)
+            .map(|flush| flush.flush())
+            .map_err(into_4kib_error),
+        PageSize::Size1GiB => mapper
+            .map_to(Page::<Size1GiB>::containing_address(virt), PhysFrame::containing_address(phys), flags, frame_allocator)
+            .map(|flush| flush.flush())
+            .map_err(into_4kib_error),
+    }
+}
+
+fn into_4kib_error<S: x86_64::structures::paging::PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+// 上の段のエントリにも付いていないと効かない権限
+const PARENT_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits() | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+// addr をマップしているエントリと、それがあるテーブルの段
+// inspect::translate と違って CR3 ではなく mapper のテーブルをたどる
+// たどる途中のエントリには parent_flags を足す(map_to は上の段に最初の権限しか付けないので)
+fn leaf_entry<'a>(
+    mapper: &'a mut OffsetPageTable,
+    addr: VirtAddr,
+    parent_flags: PageTableFlags,
+) -> Result<(&'a mut PageTableEntry, u8), NotMapped> {
+    let physical_memory_offset = mapper.phys_offset();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table: &'a mut PageTable = mapper.level_4_table();
+    for (i, &index) in indexes.iter().enumerate() {
+        let level = 4 - i as u8;
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(NotMapped { level });
+        }
+        if level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            return Ok((&mut table[index], level));
+        }
+        if !flags.contains(parent_flags) {
+            table[index].set_flags(flags | parent_flags);
+        }
+        let frame = PhysFrame::containing_address(table[index].addr());
+        table = unsafe { &mut *super::table_ptr(frame, physical_memory_offset) };
+    }
+    unreachable!()
+}
+
+// level 段目のエントリが指す大きなページを、1段下のテーブルの 512 個のページに分ける
+// 分けた後の各ページは元の権限を引き継ぎ、エントリ自身は新しいテーブルを指す
+fn split(
+    entry: &mut PageTableEntry,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ProtectError> {
+    let table_frame = frame_allocator.allocate_frame().ok_or(ProtectError::FrameAllocationFailed)?;
+    let table = unsafe { &mut *super::table_ptr(table_frame, physical_memory_offset) };
+
+    let flags = entry.flags();
+    let child_size = PageSize::at_level(level - 1).bytes();
+    // 4KiB のエントリでは HUGE_PAGE の位置のビットは PAT なので落とす
+    let child_flags = if level - 1 == 1 { flags - PageTableFlags::HUGE_PAGE } else { flags };
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(entry.addr() + i as u64 * child_size, child_flags);
+    }
+
+    // 権限は下の段で決めるので、テーブルを指すエントリは緩くしておく
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_addr(table_frame.start_address(), table_flags);
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    structures::paging::PageTable,
+    structures::paging::OffsetPageTable,
+    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, FrameDeallocator},
+    structures::paging::{PageTableFlags, Translate, mapper::MapToError},
+};
+use x86_64::registers::control::Cr3;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use buddy::BuddyAllocator;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::allocator::Locked;
+use crate::elf::ElfFile;
+
+pub mod buddy;
+pub mod fault;
+pub mod inspect;
+pub mod mapping;
+pub mod protection;
+pub mod slab;
+pub mod stack;
+pub mod vma;
+
+// 起動後にページを追加でマップするときに使う、カーネル全体で共有するページテーブルとフレームアロケータ
+// ヒープを広げるときにもこのロックを取るので、ロックを持ったままヒープを使ってはいけない
+// ロックを持っている間は割込みを止めておき(Locked)、他のスレッドに切り替わってロックを持ったまま待たされることがないようにする
+// こうしておくと、ヒープを広げるときや例外ハンドラで try_lock が失敗するのは、同じ流れの中でロックを持っているときだけになる
+pub static MAPPER: Locked<Option<OffsetPageTable<'static>>> = Locked::new(None);
+pub static FRAME_ALLOCATOR: Locked<Option<BuddyAllocator>> = Locked::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// 起動時の初期化が済んだら、ページテーブルとフレームアロケータを共有できるように預ける
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BuddyAllocator) {
+    PHYSICAL_MEMORY_OFFSET.store(mapper.phys_offset().as_u64(), Ordering::Relaxed);
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+// 物理メモリ全体をマップしてある仮想アドレスを覚える(init が呼ぶ)
+// ページテーブルを調べる処理(inspect や backtrace)は、ここから物理メモリのマッピングを使う
+pub fn set_physical_memory_offset(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+}
+
+// 物理メモリ全体をマップしてある仮想アドレス(init の後で使える)
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+// ブートローダが物理メモリに読み込んだカーネルの ELF ファイル
+// メモリマップの Kernel の領域がそれにあたる
+pub fn kernel_image(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+) -> Option<ElfFile<'static>> {
+    memory_map.iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .find_map(|region| {
+            let start = physical_memory_offset + region.range.start_addr();
+            let len = (region.range.end_addr() - region.range.start_addr()) as usize;
+            let data = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len) };
+            ElfFile::parse(data).ok()
+        })
+}
+
+// ブートローダのメモリマップのエントリ数の上限と同じ
+const MAX_REGIONS: usize = 64;
+
+// まだ一度も割り当てていないフレームの区間 [next, end)
+#[derive(Clone, Copy)]
+struct FrameRange {
+    next: u64,
+    end: u64,
+}
+
+// ブートローダから渡されたメモリマップを使って空きフレームを割り当てるアロケータ
+// 使用可能な区間を先頭から切り出していき、解放されたフレームはフリーリストにつないで再利用する
+// フリーリストの次のフレームのアドレスは、空いているフレーム自身の先頭 8 バイトに書いておく
+// (物理メモリ全体がマップされているので、物理アドレスにオフセットを足せば読み書きできる)
+pub struct BootInfoFrameAllocatior {
+    ranges: [FrameRange; MAX_REGIONS],
+    range_count: usize,
+    // 今切り出している区間(これより前の区間は使い切っている)
+    current: usize,
+    free_list: Option<PhysFrame>,
+    free_count: u64,
+    physical_memory_offset: VirtAddr,
+}
+
+impl BootInfoFrameAllocatior {
+    /// # Safety
+    /// memory_map の Usable な領域が本当に他で使われていないこと(この関数側では確かめられない)
+    /// physical_memory_offset から物理メモリ全体がマップされていること
+    pub unsafe fn init(
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let mut allocator = BootInfoFrameAllocatior {
+            ranges: [FrameRange { next: 0, end: 0 }; MAX_REGIONS],
+            range_count: 0,
+            current: 0,
+            free_list: None,
+            free_count: 0,
+            physical_memory_offset,
+        };
+
+        let usable_regions = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            // フレームの境界にそろっていない部分は使わない
+            let next = PhysAddr::new(region.range.start_addr()).align_up(4096u64).as_u64();
+            let end = PhysAddr::new(region.range.end_addr()).align_down(4096u64).as_u64();
+            if next < end && allocator.range_count < MAX_REGIONS {
+                allocator.ranges[allocator.range_count] = FrameRange { next, end };
+                allocator.range_count += 1;
+            }
+        }
+        allocator
+    }
+
+    // 残っているフレームの数
+    pub fn free_frames(&self) -> u64 {
+        let untouched: u64 = self.ranges[self.current..self.range_count].iter()
+            .map(|range| (range.end - range.next) / 4096)
+            .sum();
+        untouched + self.free_count
+    }
+
+    // ここまでに割り当てたフレームはそのままにして、残りをバディアロケータに引き継ぐ
+    // 管理用のバイト列を置ける区間がなければ None
+    pub fn into_buddy(mut self) -> Option<BuddyAllocator> {
+        let ranges = self.current..self.range_count;
+        let frame_count = self.ranges[..self.range_count].iter().map(|r| r.end / 4096).max()?;
+        let metadata_size = BuddyAllocator::metadata_frames(frame_count) * 4096;
+
+        // 管理用のバイト列は、残っている区間のうち収まるものの先頭から切り出す
+        let range = self.ranges[ranges.clone()].iter_mut()
+            .find(|r| r.end - r.next >= metadata_size)?;
+        let metadata = PhysAddr::new(range.next);
+        range.next += metadata_size;
+
+        let mut buddy = unsafe {
+            BuddyAllocator::new(frame_count, metadata, self.physical_memory_offset)
+        };
+        for range in &self.ranges[ranges] {
+            unsafe { buddy.add_range(PhysAddr::new(range.next), PhysAddr::new(range.end)) };
+        }
+        while let Some(frame) = self.free_list {
+            self.free_list = unsafe { self.next_link(frame).read() };
+            unsafe { buddy.deallocate_frame(frame) };
+        }
+        Some(buddy)
+    }
+
+    fn next_link(&self, frame: PhysFrame) -> *mut Option<PhysFrame> {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocatior {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // 解放されたフレームがあれば先に使う
+        if let Some(frame) = self.free_list {
+            self.free_list = unsafe { self.next_link(frame).read() };
+            self.free_count -= 1;
+            return Some(frame);
+        }
+
+        while self.current < self.range_count {
+            let range = &mut self.ranges[self.current];
+            if range.next < range.end {
+                let frame = PhysFrame::containing_address(PhysAddr::new(range.next));
+                range.next += 4096;
+                return Some(frame);
+            }
+            self.current += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocatior {
+    // このアロケータから割り当てたフレームで、もうどこからも使われていないこと
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.next_link(frame).write(self.free_list);
+        self.free_list = Some(frame);
+        self.free_count += 1;
+    }
+}
+
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>
+) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    // 物理アドレス 0xb8000 を含むフレームを作成(ページテーブルはここを指すように修正される)
+    // フレームが物理、ページは仮想
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+
+    // 新たなマッピングを追加(page で渡される仮想アドレスを 0xb8000 にマッピング)
+    // 戻り値の型の map_to は、追加したページ をTLB からクリアする flush メソッドを持っている
+    let map_to_result = unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+    map_to_result.expect("map_to failed").flush();
+}
+
+// pub struct EmptyFrameAllocator;
+
+// unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+//     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+//         None
+//     }
+// }
+
+// mut な参照を返す関数なので、何回も呼ばれて複数の名前で同一のメモリを参照すると危険
+// init からのみ呼び出すようにする
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+ -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    // ブートローダが Cr3 を作ってくれている
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    // Cr3 に書かれた物理アドレスにオフセットを足した仮想アドレスを作る
+    let virt = physical_memory_offset + phys.as_u64();
+    // 仮想アドレスをポインタにして返す
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    set_physical_memory_offset(physical_memory_offset);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    // OffsetPageTable は固定オフセットで全物理メモリをマップする場合に使えるライブラリ関数
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+// プロセスごとのアドレス空間(専用の L4 ページテーブル)
+// カーネルの L4 エントリは今のアドレス空間からコピーし、L3 以下のテーブルを共有するので、
+// カーネルのマッピングはどのアドレス空間からも同じように見える
+// ただし、作った後でカーネル側に新しく追加された L4 エントリは反映されない
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    pub fn new(
+        physical_memory_offset: VirtAddr,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Option<Self> {
+        let level_4_frame = frame_allocator.allocate_frame()?;
+        let mut space = AddressSpace { level_4_frame, physical_memory_offset };
+
+        // ブートローダはカーネルも下半分に置くので、上半分かどうかではなく
+        // USER_ACCESSIBLE が付いていないエントリをカーネルのものとみなしてコピーする
+        let active = unsafe { &*table_ptr(Cr3::read().0, physical_memory_offset) };
+        let table = space.level_4_table();
+        table.zero();
+        for (entry, kernel_entry) in table.iter_mut().zip(active.iter()) {
+            if !kernel_entry.is_unused()
+                && !kernel_entry.flags().contains(PageTableFlags::USER_ACCESSIBLE)
+            {
+                *entry = kernel_entry.clone();
+            }
+        }
+        Some(space)
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // このアドレス空間のページテーブルを操作する(切り替えていなくても使える)
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = self.physical_memory_offset;
+        unsafe { OffsetPageTable::new(self.level_4_table(), offset) }
+    }
+
+    // [start, start + size) に 0 で埋めたフレームを割り当てる
+    pub fn map_user_region(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::containing_address(start + size.max(1) - 1u64);
+        let offset = self.physical_memory_offset;
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            let frame = frame_allocator.allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                // 前に使っていたデータが見えないようにする
+                let dest = offset + frame.start_address().as_u64();
+                core::ptr::write_bytes(dest.as_mut_ptr::<u8>(), 0, 4096);
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    }
+
+    // 切り替えずに、物理メモリのマッピング経由でこのアドレス空間に書き込む
+    // マップされていないページがあれば、そこまで書いて Err を返す
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), VirtAddr> {
+        let offset = self.physical_memory_offset;
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let dest = addr + written;
+            let phys = mapper.translate_addr(dest).ok_or(dest)?;
+            // ページの境界を越えないように分けて書く
+            let len = (4096 - usize::from(dest.page_offset())).min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    (offset + phys.as_u64()).as_mut_ptr::<u8>(),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    // [start, start + size) がカーネルからコピーした L4 エントリの範囲にかかっているか
+    // そこにユーザのページを作ると、カーネルと共有しているテーブルを書き換えてしまう
+    pub fn overlaps_kernel(&self, start: VirtAddr, size: u64) -> bool {
+        let table = unsafe { &*table_ptr(self.level_4_frame, self.physical_memory_offset) };
+        let first = u16::from(start.p4_index());
+        let last = u16::from((start + size.max(1) - 1u64).p4_index());
+        (first..=last).any(|index| {
+            let entry = &table[usize::from(index)];
+            !entry.is_unused() && !entry.flags().contains(PageTableFlags::USER_ACCESSIBLE)
+        })
+    }
+
+    // CR3 を書き換えてこのアドレス空間に切り替える
+    /// # Safety
+    /// 切り替えている間は AddressSpace を捨ててはいけない(free すると使用中のページテーブルを解放してしまう)
+    /// 今動いているコードとスタックが、このアドレス空間でも同じ場所にマップされていること
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    // ユーザ部分のページとページテーブル、L4 テーブル自身のフレームを解放する
+    // カーネルと共有しているエントリ(USER_ACCESSIBLE なし)の先には触らない
+    /// # Safety
+    /// このアドレス空間に切り替えたまま呼んではいけない(今の CR3 と同じなら assert で止める)
+    /// ユーザ部分のページへの参照やポインタがカーネル側に残っていないこと
+    /// frame_deallocator は、このアドレス空間のフレームを割り当てたアロケータであること
+    pub unsafe fn free(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        assert_ne!(Cr3::read().0, self.level_4_frame, "cannot free the active address space");
+        free_table(self.level_4_frame, 4, self.physical_memory_offset, frame_deallocator);
+    }
+
+    fn level_4_table(&mut self) -> &mut PageTable {
+        unsafe { &mut *table_ptr(self.level_4_frame, self.physical_memory_offset) }
+    }
+}
+
+// level 段目のテーブルから下をたどってフレームを解放する
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = &*table_ptr(frame, physical_memory_offset);
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            continue;
+        }
+        // ユーザのページは 4KiB でしかマップしていない
+        assert!(!flags.contains(PageTableFlags::HUGE_PAGE), "huge page in user space");
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(child, level - 1, physical_memory_offset, frame_deallocator);
+        } else {
+            frame_deallocator.deallocate_frame(child);
+        }
+    }
+    frame_deallocator.deallocate_frame(frame);
+}
+
+fn table_ptr(frame: PhysFrame, physical_memory_offset: VirtAddr) -> *mut PageTable {
+    (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
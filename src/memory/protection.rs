@@ -0,0 +1,109 @@
+use alloc::vec::Vec;
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    VirtAddr,
+    registers::control::{Cr0, Cr0Flags},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::PageTableFlags,
+};
+use crate::elf::ProgramHeader;
+use super::inspect::{self, MappedRange};
+use super::mapping::{self, ProtectError};
+
+// W^X(書き込めるページは実行できない)
+// ブートローダはカーネルのセグメント以外を読み書き実行できるようにマップしているので、
+// 起動時にカーネルの ELF のプログラムヘッダに合わせて張り直し、残りの書き込めるページは全て実行不可にする
+// 張り直した後に新しくマップするページ(ヒープ、スタック、MMIO など)は、マップする側で NO_EXECUTE を付ける
+
+#[derive(Debug)]
+pub enum ProtectionError {
+    // メモリマップにカーネルの ELF が見つからない
+    KernelImageNotFound,
+    Protect(ProtectError),
+}
+
+impl From<ProtectError> for ProtectionError {
+    fn from(error: ProtectError) -> Self {
+        ProtectionError::Protect(error)
+    }
+}
+
+// NO_EXECUTE を付けたページを作る前に呼ぶ(NXE が無効だと NO_EXECUTE は予約ビットなのでページフォルトになる)
+// CR0.WP を立てると、カーネルも読み出し専用のページに書き込めなくなる
+pub fn enable_nx_and_write_protect() {
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+}
+
+// カーネルの .text を読み出しと実行だけ、.rodata を読み出しだけ、.data と .bss を読み書きだけにし、
+// ほかの書き込めるページを実行不可にする
+// memory::install の後に呼ぶ
+pub fn protect_kernel(memory_map: &'static MemoryMap) -> Result<(), ProtectionError> {
+    let kernel = super::kernel_image(memory_map, super::physical_memory_offset())
+        .ok_or(ProtectionError::KernelImageNotFound)?;
+    {
+        let mut mapper = super::MAPPER.lock();
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        let mapper = mapper.as_mut().expect("memory::install has not been called");
+        let frame_allocator = frame_allocator.as_mut().expect("memory::install has not been called");
+        for header in kernel.program_headers().filter(|header| header.is_load()) {
+            let start = VirtAddr::new(header.p_vaddr);
+            mapping::protect(mapper, start, header.p_memsz, segment_flags(&header), frame_allocator)?;
+        }
+    }
+
+    // ページテーブルのロックを持ったままヒープを使えないので、先に範囲を集めておく
+    let mut ranges = Vec::new();
+    inspect::for_each_range(|range| {
+        if is_writable_executable(range) {
+            ranges.push(*range);
+        }
+    });
+    let mut mapper = super::MAPPER.lock();
+    let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().expect("memory::install has not been called");
+    let frame_allocator = frame_allocator.as_mut().expect("memory::install has not been called");
+    for range in ranges {
+        let flags = range.flags | PageTableFlags::NO_EXECUTE;
+        mapping::protect(mapper, range.start, range.size, flags, frame_allocator)?;
+    }
+    Ok(())
+}
+
+// 書き込めて実行もできる最初の範囲
+pub fn find_writable_executable() -> Option<MappedRange> {
+    let mut found = None;
+    inspect::for_each_range(|range| {
+        if found.is_none() && is_writable_executable(range) {
+            found = Some(*range);
+        }
+    });
+    found
+}
+
+// 起動の最後に呼び、W^X が守られていなければ止める
+pub fn check() {
+    if let Some(range) = find_writable_executable() {
+        panic!(
+            "W^X violation: {:#x}-{:#x} is writable and executable",
+            range.start.as_u64(), range.last().as_u64()
+        );
+    }
+}
+
+fn segment_flags(header: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if header.is_writable() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !header.is_executable() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn is_writable_executable(range: &MappedRange) -> bool {
+    range.flags.contains(PageTableFlags::WRITABLE) && !range.flags.contains(PageTableFlags::NO_EXECUTE)
+}
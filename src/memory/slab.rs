@@ -0,0 +1,257 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+use x86_64::{
+    PhysAddr,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+};
+
+// スラブアロケータ
+// 同じ型のオブジェクトだけを置くキャッシュで、フレーム1つ(スラブ)を同じ大きさのスロットに区切って使う
+// スラブの先頭にはヘッダを置き、空いているスロットはスロット自身の中に書いた単方向リストでつなぐ
+// 解放するときは、アドレスを 4KiB 境界に切り下げればヘッダが見つかる
+//
+// スラブは空きスロットの有無で partial / full / empty の3つのリストに分けておき、
+// 割り当ては partial、なければ empty から取る(それもなければフレームアロケータから新しく取る)
+// 空になったスラブはすぐには返さず、shrink でまとめてフレームアロケータに返す(キャッシュを drop したときは全部返す)
+//
+// フレームは memory::FRAME_ALLOCATOR から取るので、memory::install の後でないと使えない
+// また、FRAME_ALLOCATOR のロックを持ったまま割り当てや shrink をしてはいけない
+
+const SLAB_SIZE: usize = 4096;
+
+// スラブの先頭に置くヘッダ
+// リストの途中から外すので双方向にする
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    // 空いているスロットのリスト
+    free: *mut FreeSlot,
+    in_use: usize,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+// スラブの双方向リスト
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: ptr::null_mut(), len: 0 }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+// キャッシュごとの統計
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+pub struct SlabCache<T> {
+    name: &'static str,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    objects_in_use: usize,
+    allocations: usize,
+    frees: usize,
+    _marker: PhantomData<T>,
+}
+
+// スラブは他から参照されないので、T を送れるならキャッシュごと他のスレッドに渡してよい
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    // スロットの大きさ(空きリストのポインタが入るだけの大きさとアラインメントは確保する)
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() {
+        align_of::<T>()
+    } else {
+        align_of::<FreeSlot>()
+    };
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeSlot>()
+        };
+        size.next_multiple_of(Self::SLOT_ALIGN)
+    };
+    // ヘッダの後ろ、最初のスロットの位置
+    const FIRST_SLOT: usize = size_of::<Slab>().next_multiple_of(Self::SLOT_ALIGN);
+    const OBJECTS_PER_SLAB: usize = (SLAB_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        // スラブ1つに1個も入らない型はスラブで管理できない
+        assert!(Self::OBJECTS_PER_SLAB > 0, "object too large for a slab");
+        SlabCache {
+            name,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            objects_in_use: 0,
+            allocations: 0,
+            frees: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    // value をスロットに置いてそのポインタを返す
+    // フレームが足りなければ None を返す(value はそこで drop される)
+    pub fn alloc(&mut self, value: T) -> Option<NonNull<T>> {
+        let slab = unsafe {
+            match self.partial.pop().or_else(|| self.empty.pop()) {
+                Some(slab) => slab,
+                None => self.new_slab()?,
+            }
+        };
+
+        unsafe {
+            let slot = (*slab).free;
+            (*slab).free = (*slot).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.full.push(slab);
+            } else {
+                self.partial.push(slab);
+            }
+
+            let object = slot as *mut T;
+            object.write(value);
+            self.objects_in_use += 1;
+            self.allocations += 1;
+            Some(NonNull::new_unchecked(object))
+        }
+    }
+
+    // オブジェクトを drop してスロットを空ける
+    /// # Safety
+    /// object はこのキャッシュの alloc が返したもので、まだ free していないこと
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        let object = object.as_ptr();
+        ptr::drop_in_place(object);
+
+        let slab = (object as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        if (*slab).free.is_null() {
+            self.full.remove(slab);
+        } else {
+            self.partial.remove(slab);
+        }
+
+        let slot = object as *mut FreeSlot;
+        (*slot).next = (*slab).free;
+        (*slab).free = slot;
+        (*slab).in_use -= 1;
+        if (*slab).in_use == 0 {
+            self.empty.push(slab);
+        } else {
+            self.partial.push(slab);
+        }
+
+        self.objects_in_use -= 1;
+        self.frees += 1;
+    }
+
+    // 空のスラブをすべてフレームアロケータに返し、返したスラブの数を返す
+    pub fn shrink(&mut self) -> usize {
+        unsafe { Self::release_slabs(&mut self.empty) }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: size_of::<T>(),
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: self.partial.len + self.full.len + self.empty.len,
+            empty_slabs: self.empty.len,
+            objects_in_use: self.objects_in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+        }
+    }
+
+    // list のスラブをすべてフレームアロケータに返し、返したスラブの数を返す
+    unsafe fn release_slabs(list: &mut SlabList) -> usize {
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        let frame_allocator = match frame_allocator.as_mut() {
+            Some(frame_allocator) => frame_allocator,
+            None => return 0,
+        };
+        let offset = super::physical_memory_offset();
+
+        let mut freed = 0;
+        while let Some(slab) = list.pop() {
+            let phys = PhysAddr::new(slab as u64 - offset.as_u64());
+            frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys));
+            freed += 1;
+        }
+        freed
+    }
+
+    // フレームアロケータから新しいスラブを取り、全スロットを空きリストにつなぐ
+    unsafe fn new_slab(&mut self) -> Option<*mut Slab> {
+        let frame: PhysFrame<Size4KiB> = super::FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()?;
+        let base = super::physical_memory_offset() + frame.start_address().as_u64();
+        let slab = base.as_mut_ptr::<Slab>();
+
+        let mut free = ptr::null_mut();
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot = (base + (Self::FIRST_SLOT + index * Self::SLOT_SIZE) as u64).as_mut_ptr::<FreeSlot>();
+            (*slot).next = free;
+            free = slot;
+        }
+        slab.write(Slab { prev: ptr::null_mut(), next: ptr::null_mut(), free, in_use: 0 });
+        Some(slab)
+    }
+}
+
+// すべてのスラブのフレームを返す
+// 使用中のオブジェクトが残っていると、そのポインタが使えなくなってしまうので panic する
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        assert_eq!(self.objects_in_use, 0, "slab cache {} dropped with objects in use", self.name);
+        unsafe { Self::release_slabs(&mut self.empty) };
+    }
+}
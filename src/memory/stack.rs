@@ -0,0 +1,129 @@
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+};
+use super::vma::{self, Purpose, VmaError};
+
+// カーネルスタックの割り当て
+// スタックごとに vma から「ガード + スタック」の範囲を取り、上側のスタックの部分だけをマップする
+// 下のガードはマップしないままにしておくので、あふれるとガードに触ってページフォルトになる
+// vma の範囲にはスタックの名前を付けておき、フォルトしたアドレスからあふれたスタックを引けるようにする
+//
+// フレームは memory::MAPPER と memory::FRAME_ALLOCATOR を使うので、memory::install の後でないと使えない
+
+// スタックの下に空けておくガードの大きさ
+// 大きなローカル変数でガードを飛び越えて、隣の範囲を壊さないよう余裕を持たせる
+const GUARD_SIZE: u64 = 64 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+pub const MAX_STACK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum StackError {
+    TooLarge,
+    Vma(VmaError),
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<VmaError> for StackError {
+    fn from(error: VmaError) -> Self {
+        StackError::Vma(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        StackError::Map(error)
+    }
+}
+
+// drop するとページのマップを外してフレームを返す
+pub struct KernelStack {
+    // ガードも含めた vma の範囲の先頭
+    area_start: VirtAddr,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    // スタックの先頭(ここから下に伸びる)
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    // 解放せずにずっと使い続ける(TSS に登録するスタックなど)
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top;
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if let Err(error) = vma::release(self.area_start) {
+            panic!("failed to release a kernel stack: {:?}", error);
+        }
+    }
+}
+
+// size バイト(ページ単位に切り上げる)のスタックを、下にガードを付けて割り当てる
+pub fn allocate(name: &'static str, size: usize) -> Result<KernelStack, StackError> {
+    let size = (size as u64).next_multiple_of(PAGE_SIZE);
+    if size == 0 || size > MAX_STACK_SIZE as u64 {
+        return Err(StackError::TooLarge);
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let area = vma::reserve(Purpose::Stack, name, GUARD_SIZE + size, PAGE_SIZE, flags)?;
+    let bottom = area.start + GUARD_SIZE;
+    let top = area.end();
+    if let Err(error) = map_pages(bottom, top, area.flags) {
+        // 途中までマップしたページも外れる
+        if let Err(release_error) = vma::release(area.start) {
+            panic!("failed to release a kernel stack: {:?}", release_error);
+        }
+        return Err(error.into());
+    }
+    Ok(KernelStack { area_start: area.start, bottom, top })
+}
+
+// addr がカーネルスタックのガードなら、そのスタックの名前を返す
+// 例外ハンドラから呼ばれるので、ロックが取れなければ諦める
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let area = vma::find(addr)?;
+    if area.purpose == Purpose::Stack && addr < area.start + GUARD_SIZE {
+        Some(area.name)
+    } else {
+        None
+    }
+}
+
+// [bottom, top) にフレームをマップする
+// 失敗したときは途中までマップしたページが残るので、呼び出し側で範囲ごと返す
+fn map_pages(bottom: VirtAddr, top: VirtAddr, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = super::MAPPER.lock();
+    let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().expect("memory::install has not been called");
+    let frame_allocator = frame_allocator.as_mut().expect("memory::install has not been called");
+
+    let pages = Page::<Size4KiB>::range(Page::containing_address(bottom), Page::containing_address(top));
+    for page in pages {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                // マップできなかったフレームはどこからも参照されないので、ここで返す
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::PageTableFlags,
+};
+use super::mapping;
+
+// カーネルの仮想アドレス空間の割り当て(VMA: virtual memory area)
+// ヒープ、スタック、MMIO などに使う仮想アドレスの範囲を、決め打ちの定数ではなくここから重ならないように切り出す
+// 範囲ごとに用途と名前、マップするときの権限を覚えておき、例外ハンドラやシェルから引けるようにする
+// 範囲を返すときは中のページのマップを外し、デバイスのもの(MMIO)でなければフレームも返す
+//
+// ヒープの範囲もここから取るので、記録は固定長の表に置く
+
+// 切り出す仮想アドレスの範囲(ブートローダが使わない L4 エントリの範囲)
+const VMA_START: u64 = 0x_4444_0000_0000;
+const VMA_END: u64 = 0x_5444_0000_0000;
+const MAX_AREAS: usize = 512;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Heap,
+    Stack,
+    // デバイスのレジスタ(物理アドレスはデバイスのものなので、返すときにフレームは返さない)
+    Mmio,
+    // プロセスごとにカーネル側で使う範囲
+    Process,
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // シェルで桁をそろえられるよう pad で書く
+        f.pad(match self {
+            Purpose::Heap => "heap",
+            Purpose::Stack => "stack",
+            Purpose::Mmio => "mmio",
+            Purpose::Process => "process",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Area {
+    pub purpose: Purpose,
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    // この範囲にページをマップするときに使う権限
+    pub flags: PageTableFlags,
+}
+
+impl Area {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    OutOfSpace,
+    TooManyAreas,
+    // その先頭アドレスの範囲は確保されていない
+    NotFound,
+    // 大きなページを分けるためのテーブルを確保できなかった
+    FrameAllocationFailed,
+}
+
+// 例外ハンドラからも引くので、ロックを持つ間は割込みを止めておく
+static AREAS: Mutex<[Option<Area>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+
+// size バイト(ページ単位に切り上げる)の範囲を、先頭を align にそろえて確保する
+// ページはマップしないので、呼び出し側が area.flags でマップする
+pub fn reserve(
+    purpose: Purpose,
+    name: &'static str,
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+) -> Result<Area, VmaError> {
+    if size > VMA_END - VMA_START {
+        return Err(VmaError::OutOfSpace);
+    }
+    let size = size.max(1).next_multiple_of(PAGE_SIZE);
+    let align = align.max(PAGE_SIZE);
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let slot = areas.iter().position(|slot| slot.is_none()).ok_or(VmaError::TooManyAreas)?;
+
+        // 先頭から順に、重なる範囲があればその後ろにずらしていく
+        let mut start = VMA_START.next_multiple_of(align);
+        while let Some(area) = areas.iter().flatten()
+            .find(|area| start < area.end().as_u64() && area.start.as_u64() < start + size)
+        {
+            start = area.end().as_u64().next_multiple_of(align);
+        }
+        if start.checked_add(size).is_none_or(|end| end > VMA_END) {
+            return Err(VmaError::OutOfSpace);
+        }
+
+        let area = Area { purpose, name, start: VirtAddr::new(start), size, flags };
+        areas[slot] = Some(area);
+        Ok(area)
+    })
+}
+
+// start から始まる範囲のマップを外して返す
+// 外したページのフレームは、MMIO の範囲でなければフレームアロケータに返す
+pub fn release(start: VirtAddr) -> Result<Area, VmaError> {
+    let area = interrupts::without_interrupts(|| {
+        AREAS.lock().iter().flatten().find(|area| area.start == start).copied()
+    }).ok_or(VmaError::NotFound)?;
+
+    // 表から外す前にマップを外し、外している途中で同じ範囲が割り当て直されないようにする
+    {
+        let mut mapper = super::MAPPER.lock();
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        if let (Some(mapper), Some(frame_allocator)) = (mapper.as_mut(), frame_allocator.as_mut()) {
+            let free_frames = !matches!(area.purpose, Purpose::Mmio);
+            mapping::unmap_range(mapper, area.start, area.size, frame_allocator, free_frames)
+                .map_err(|_| VmaError::FrameAllocationFailed)?;
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        for slot in AREAS.lock().iter_mut() {
+            if matches!(slot, Some(area) if area.start == start) {
+                *slot = None;
+            }
+        }
+    });
+    Ok(area)
+}
+
+// addr を含む範囲
+// 例外ハンドラから呼ばれるので、ロックが取れなければ諦める
+pub fn find(addr: VirtAddr) -> Option<Area> {
+    let areas = AREAS.try_lock()?;
+    areas.iter().flatten().find(|area| area.contains(addr)).copied()
+}
+
+// 確保されている範囲をアドレスの順に f に渡す
+// f の中で reserve や release を呼べるよう、ロックを外してから渡す
+pub fn for_each(mut f: impl FnMut(&Area)) {
+    let mut after: Option<VirtAddr> = None;
+    loop {
+        let next = interrupts::without_interrupts(|| {
+            AREAS.lock().iter()
+                .flatten()
+                .filter(|area| after.is_none_or(|after| area.start > after))
+                .min_by_key(|area| area.start)
+                .copied()
+        });
+        match next {
+            Some(area) => {
+                f(&area);
+                after = Some(area.start);
+            }
+            None => return,
+        }
+    }
+}
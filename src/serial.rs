@@ -1,12 +1,20 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
+use core::task::Waker;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+
+// qemu ではシリアルポートはポートアドレスの 0x3f8 にマップされる
+const COM1: u16 = 0x3f8;
+// ラインステータスレジスタ(0 ビット目が受信データありを示す)
+const LINE_STATUS_OFFSET: u16 = 5;
 
 // lazy_static で囲って init が実行時に1度だけ呼ばれるようにする
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        // qemu ではシリアルポートはポートアドレスの 0x3f8 にマップされる
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -15,7 +23,87 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed.");
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed.");
+    });
+}
+
+// 受信割込み(IRQ4)で受け取ったデータを溜めておくキュー
+// キーボードと同じく、割込みハンドラでヒープを確保しないように事前に確保する
+static RECEIVE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+const RECEIVE_QUEUE_SIZE: usize = 256;
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// 受信キューを作る(ヒープの初期化後に呼ぶ)
+// 呼ばれるまでは try_read_byte がポートを直接ポーリングする
+pub fn init() {
+    RECEIVE_QUEUE
+        .try_init_once(|| ArrayQueue::new(RECEIVE_QUEUE_SIZE))
+        .expect("serial::init should only be called once");
+}
+
+// 受信割込みのハンドラから呼ばれる
+// 届いているデータを全部キューに移す
+pub(crate) fn receive_interrupt() {
+    use x86_64::instructions::port::Port;
+
+    let mut line_status = Port::<u8>::new(COM1 + LINE_STATUS_OFFSET);
+    let mut data = Port::<u8>::new(COM1);
+    unsafe {
+        while line_status.read() & 1 != 0 {
+            let byte = data.read();
+            if let Ok(queue) = RECEIVE_QUEUE.try_get() {
+                // あふれた分は捨てる(ここで print すると再帰的に割込みが起きかねない)
+                let _ = queue.push(byte);
+            }
+        }
+    }
+    WAKER.wake();
+}
+
+// 受信キューから1バイト取り出す
+pub fn pop_received() -> Option<u8> {
+    RECEIVE_QUEUE.try_get().ok().and_then(|queue| queue.pop().ok())
+}
+
+// 受信キューに読んでいないデータがあるか
+// serial::init の前は受信割込みを使わないので、いつも false になる
+pub fn has_received() -> bool {
+    RECEIVE_QUEUE.try_get().is_ok_and(|queue| !queue.is_empty())
+}
+
+pub fn register_waker(waker: &Waker) {
+    WAKER.register(waker);
+}
+
+pub fn take_waker() {
+    WAKER.take();
+}
+
+// 受信データがあれば1バイト読む(データがなければ待たずに None を返す)
+pub fn try_read_byte() -> Option<u8> {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    if RECEIVE_QUEUE.try_get().is_ok() {
+        return pop_received();
+    }
+
+    interrupts::without_interrupts(|| {
+        // 送信中の処理と混ざらないようにロックだけ取っておく
+        let _serial = SERIAL1.lock();
+        let mut line_status = Port::<u8>::new(COM1 + LINE_STATUS_OFFSET);
+        let mut data = Port::<u8>::new(COM1);
+        unsafe {
+            if line_status.read() & 1 != 0 {
+                Some(data.read())
+            } else {
+                None
+            }
+        }
+    })
 }
 
 #[macro_export]
@@ -0,0 +1,229 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::VirtAddr;
+use crate::{allocator, keyboard, memory, thread, usermode};
+use crate::memory::inspect::{self, NotMapped, Permissions};
+
+// 動いているカーネルを手で調べるためのコマンドシェル
+// 入力は PS/2 キーボードとシリアルポートのどちらからでも受け付け、
+// 出力は VGA とシリアルの両方に書く
+// ページテーブルとフレームアロケータは memory::install で共有されたものを使う
+
+// VGA とシリアルの両方に出力する
+macro_rules! shell_print {
+    ($($arg:tt)*) => {{
+        $crate::print!($($arg)*);
+        $crate::serial_print!($($arg)*);
+    }};
+}
+
+macro_rules! shell_println {
+    () => (shell_print!("\n"));
+    ($($arg:tt)*) => (shell_print!("{}\n", format_args!($($arg)*)));
+}
+
+const PROMPT: &str = "> ";
+
+struct Command {
+    usage: &'static str,
+    description: &'static str,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { usage: "help", description: "show this message" },
+    Command { usage: "meminfo", description: "show the physical memory map" },
+    Command { usage: "pagetable <addr>", description: "translate a virtual address" },
+    Command { usage: "mappings", description: "list mapped virtual address ranges" },
+    Command { usage: "areas", description: "list reserved kernel virtual memory areas" },
+    Command { usage: "heap", description: "show kernel heap usage" },
+    Command { usage: "threads", description: "list kernel threads" },
+    Command { usage: "user <program>", description: "run a built-in program in ring 3" },
+    Command { usage: "clear", description: "clear the screen" },
+    Command { usage: "reboot", description: "reset the machine" },
+    Command { usage: "panic", description: "trigger a kernel panic" },
+];
+
+pub struct Shell {
+    memory_map: &'static MemoryMap,
+}
+
+impl Shell {
+    pub fn new(memory_map: &'static MemoryMap) -> Self {
+        Shell { memory_map }
+    }
+
+    pub fn run(&mut self) -> ! {
+        shell_println!("blog_os shell: type `help` for a list of commands");
+        loop {
+            shell_print!("{}", PROMPT);
+            let line = keyboard::read_line();
+            self.execute(line.trim());
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return,
+        };
+
+        match command {
+            "help" => help(),
+            "meminfo" => self.meminfo(),
+            "pagetable" => match args.next().and_then(parse_address) {
+                Some(addr) => self.pagetable(addr),
+                None => shell_println!("usage: pagetable <addr>"),
+            },
+            "mappings" => mappings(),
+            "areas" => areas(),
+            "heap" => heap(),
+            "threads" => threads(),
+            "user" => match args.next() {
+                Some(name) => self.user(name),
+                None => shell_println!("usage: user <{}>", usermode::PROGRAMS.join("|")),
+            },
+            "clear" => clear(),
+            "reboot" => reboot(),
+            "panic" => panic!("panic requested from the shell"),
+            _ => shell_println!("unknown command: {}", command),
+        }
+    }
+
+    fn meminfo(&self) {
+        let mut usable = 0;
+        for region in self.memory_map.iter() {
+            let start = region.range.start_addr();
+            let end = region.range.end_addr();
+            shell_println!("{:#012x}-{:#012x} {:?}", start, end, region.region_type);
+            if region.region_type == MemoryRegionType::Usable {
+                usable += end - start;
+            }
+        }
+        shell_println!("usable: {} KiB", usable / 1024);
+        // 表示中にヒープを広げようとしてもロックが取れるように、先に値だけ読んでおく
+        let free_frames = memory::FRAME_ALLOCATOR.lock().as_ref().map(|fa| fa.free_frames());
+        if let Some(free_frames) = free_frames {
+            shell_println!("free frames: {}", free_frames);
+        }
+    }
+
+    fn pagetable(&self, addr: VirtAddr) {
+        match inspect::translate(addr) {
+            Ok(translation) => {
+                shell_println!(
+                    "{:?} -> {:?} ({} page, level {})",
+                    addr, translation.phys, translation.page_size, translation.level
+                );
+                shell_println!("{} {:?}", Permissions(translation.flags), translation.flags);
+            }
+            Err(NotMapped { level }) => shell_println!("{:?} is not mapped (level {})", addr, level),
+        }
+    }
+
+    fn user(&self, name: &str) {
+        let program = match usermode::program(name) {
+            Some(program) => program,
+            None => {
+                shell_println!("unknown program: {}", name);
+                return;
+            }
+        };
+        match usermode::run(program) {
+            Ok(usermode::ExitStatus::Exited(code)) => {
+                shell_println!("{} exited with code {:#x}", name, code)
+            }
+            Ok(usermode::ExitStatus::Killed) => shell_println!("{} was killed", name),
+            Err(error) => shell_println!("failed to run {}: {:?}", name, error),
+        }
+    }
+}
+
+fn help() {
+    for command in COMMANDS {
+        shell_println!("{:<18} {}", command.usage, command.description);
+    }
+}
+
+fn mappings() {
+    inspect::for_each_range(|range| {
+        shell_println!(
+            "{:#018x}-{:#018x} -> {:#012x} {} {}",
+            range.start.as_u64(), range.last().as_u64(), range.phys_start.as_u64(),
+            Permissions(range.flags), range.page_size
+        );
+    });
+}
+
+fn areas() {
+    memory::vma::for_each(|area| {
+        shell_println!(
+            "{:#018x}-{:#018x} {:<7} {} {}",
+            area.start.as_u64(), area.end().as_u64() - 1,
+            area.purpose, Permissions(area.flags), area.name
+        );
+    });
+}
+
+fn heap() {
+    let usage = allocator::heap_usage();
+    let size = allocator::heap_size();
+    shell_println!(
+        "heap: {:#x}-{:#x} ({} KiB, up to {} KiB)",
+        allocator::heap_start(),
+        allocator::heap_start() + size,
+        size / 1024,
+        allocator::heap_limit() / 1024
+    );
+    shell_println!("used: {} bytes, free: {} bytes", usage.used, usage.free);
+
+    let stats = allocator::stats();
+    shell_println!(
+        "in use: {} bytes (peak {} bytes), allocations: {}, frees: {}",
+        stats.bytes_in_use, stats.peak_bytes, stats.allocations, stats.frees
+    );
+    for (index, count) in stats.histogram.iter().enumerate() {
+        match allocator::stats::bucket_limit(index) {
+            Some(limit) => shell_println!("  <= {:>5}: {}", limit, count),
+            None => shell_println!("   > {:>5}: {}", allocator::stats::bucket_limit(index - 1).unwrap(), count),
+        }
+    }
+}
+
+fn threads() {
+    for info in thread::list() {
+        shell_println!("{:>4} {:<16} {:?}", info.id.as_u64(), info.name, info.state);
+    }
+}
+
+fn clear() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        crate::vga_buffer::WRITER.lock().clear_screen();
+    });
+    // シリアル側は ANSI エスケープシーケンスで端末を消す
+    crate::serial_print!("\x1b[2J\x1b[H");
+}
+
+fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+
+    // キーボードコントローラのリセット線(コマンド 0xfe)で CPU をリセットする
+    unsafe { Port::<u8>::new(0x64).write(0xfe) };
+
+    // 効かなかった場合は空の IDT で例外を起こし、トリプルフォルトでリセットする
+    use x86_64::structures::DescriptorTablePointer;
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe { x86_64::instructions::tables::lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+// 0x で始まれば 16 進数、それ以外は 10 進数として読む
+fn parse_address(s: &str) -> Option<VirtAddr> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    VirtAddr::try_new(value).ok()
+}
@@ -0,0 +1,109 @@
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use crate::{gdt, print, serial_print, usermode};
+
+// syscall 命令によるシステムコールの入口
+// 引数の渡し方は Linux に合わせて、rax がシステムコール番号、rdi, rsi, rdx が引数、rax が戻り値
+// syscall は rcx に戻り先の rip、r11 に rflags を入れるので、この2つはユーザ側で壊れる
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+
+// 失敗したときの戻り値(-1)
+pub const ERROR: u64 = u64::MAX;
+
+// syscall はスタックを切り替えてくれないので、入口で自分で切り替える
+static mut KERNEL_RSP: u64 = 0;
+static mut USER_RSP: u64 = 0;
+
+// gdt::init の後に呼ぶ
+pub fn init() {
+    let selectors = gdt::selectors();
+    // sysret では CS = STAR[63:48] + 16, SS = STAR[63:48] + 8 になり、
+    // syscall では CS = STAR[47:32], SS = STAR[47:32] + 8 になる
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    ).expect("invalid GDT layout for syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // カーネルに入った直後に割込みが入らないよう、IF を落とす
+    // DF も落としておかないと、ユーザが立てたまま入ってきたときに文字列命令が逆向きに動く
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG);
+
+    unsafe {
+        KERNEL_RSP = gdt::privilege_stack_top().as_u64();
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+// syscall_entry から呼ばれる(割込みは禁止されている)
+extern "C" fn syscall_dispatch(number: u64, arg0: u64, arg1: u64, _arg2: u64) -> u64 {
+    match number {
+        SYS_EXIT => usermode::exit(arg0),
+        SYS_WRITE => sys_write(arg0, arg1),
+        _ => ERROR,
+    }
+}
+
+// write(buf, len): 文字列を VGA とシリアルに出力し、書いたバイト数を返す
+fn sys_write(buf: u64, len: u64) -> u64 {
+    // ユーザがカーネルのアドレスを渡してきても読まない
+    if !usermode::is_user_range(buf, len) {
+        return ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(s) => {
+            print!("{}", s);
+            serial_print!("{}", s);
+            len
+        }
+        Err(_) => ERROR,
+    }
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// 呼び出し規約で caller-saved のレジスタも、rcx と r11 以外はユーザに戻す前に元に戻す
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    // 関数呼び出しの前にスタックを 16 バイト境界にそろえる
+    "sub rsp, 8",
+    // (rax, rdi, rsi, rdx) を syscall_dispatch の引数 (rdi, rsi, rdx, rcx) にずらす
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+    dispatch = sym syscall_dispatch,
+);
@@ -0,0 +1,59 @@
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::{Stream, StreamExt};
+use crate::keyboard::{self, DecodedKey, ScancodeDecoder};
+use crate::print;
+
+// キーボードのスキャンコードを非同期に読み出すストリーム
+// キューが空なら Waker を登録して Pending を返し、割込みハンドラに起こしてもらう
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        // すでにデータがあれば Waker の登録は不要
+        if let Some(scancode) = keyboard::pop_scancode() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // 登録してからもう一度確認しないと、その間に来た割込みを取りこぼす
+        keyboard::register_waker(cx.waker());
+        match keyboard::pop_scancode() {
+            Some(scancode) => {
+                keyboard::take_waker();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// 押されたキーを次々に画面に表示するタスク
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = ScancodeDecoder::new();
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Some(event) = decoder.add_byte(scancode) {
+            match decoder.process_event(event) {
+                Some(DecodedKey::Unicode(character)) => print!("{}", character),
+                Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+                None => {}
+            }
+        }
+    }
+}
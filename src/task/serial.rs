@@ -0,0 +1,57 @@
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::{Stream, StreamExt};
+use crate::serial;
+use crate::serial_print;
+
+// シリアルポートの受信データを非同期に読み出すストリーム
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        SerialStream { _private: () }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(byte) = serial::pop_received() {
+            return Poll::Ready(Some(byte));
+        }
+
+        serial::register_waker(cx.waker());
+        match serial::pop_received() {
+            Some(byte) => {
+                serial::take_waker();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// 受信した文字をそのままシリアルに送り返すタスク
+pub async fn echo_serial() {
+    let mut bytes = SerialStream::new();
+
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            b'\r' => {
+                serial_print!("\n");
+            }
+            0x20..=0x7e | b'\n' => {
+                serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
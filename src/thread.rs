@@ -0,0 +1,433 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::interrupts::ticks;
+use crate::memory::stack::{self, KernelStack};
+
+// タイマ割込みで切り替わるプリエンプティブなカーネルスレッド
+// スレッドはそれぞれ専用のスタック(下にガードページ付き)を持ち、切り替え時には callee-saved レジスタとスタックポインタだけを保存する
+// (caller-saved レジスタは、割込みハンドラか switch_context を呼ぶ側の関数がスタックに退避している)
+//
+// スケジューラのロックを取っている間は割込みを止めるか、割込みハンドラ側で try_lock して諦める
+// また、ロック中にヒープを使うと、ヒープのロックを持ったまま止まっているスレッドとデッドロックするので、
+// スレッドの管理には固定長の配列を使う
+
+const MAX_THREADS: usize = 32;
+const STACK_SIZE: usize = 4096 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    // 指定したティックまで眠る
+    Sleeping { until: u64 },
+    // 他のスレッドの終了を待つ
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    // 切り替えで止まっている間のスタックポインタ
+    saved_rsp: u64,
+    // JoinHandle が捨てられたら、終了後に誰も join しないので自動で片付ける
+    detached: bool,
+    // kernel_main のスレッドはブートローダが用意したスタックを使うので None
+    _stack: Option<KernelStack>,
+}
+
+struct Scheduler {
+    threads: [Option<Box<Thread>>; MAX_THREADS],
+    // 実行中のスレッドのスロット番号
+    current: usize,
+    // 実行できるスレッドがないときに hlt するだけのスレッド
+    idle: usize,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        const EMPTY: Option<Box<Thread>> = None;
+        Scheduler {
+            threads: [EMPTY; MAX_THREADS],
+            current: 0,
+            idle: 0,
+        }
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.threads.iter().position(|slot| slot.is_none())
+    }
+
+    fn find(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|slot| matches!(slot, Some(t) if t.id == id))
+    }
+
+    fn thread_mut(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("thread slot is empty")
+    }
+
+    // 眠っているスレッドのうち、時間が来たものを起こす
+    fn wake_sleepers(&mut self, now: u64) {
+        for thread in self.threads.iter_mut().flatten() {
+            if let ThreadState::Sleeping { until } = thread.state {
+                if until <= now {
+                    thread.state = ThreadState::Ready;
+                }
+            }
+        }
+    }
+
+    // 次に動かすスレッドを、今のスロットの次から順に探す(ラウンドロビン)
+    fn pick_next(&self) -> usize {
+        (1..=MAX_THREADS)
+            .map(|offset| (self.current + offset) % MAX_THREADS)
+            .find(|&slot| {
+                slot != self.idle
+                    && matches!(&self.threads[slot], Some(t) if t.state == ThreadState::Ready)
+            })
+            .unwrap_or_else(|| {
+                // 今のスレッドがまだ動けるならそのまま続ける
+                let current = self.threads[self.current].as_ref().expect("no current thread");
+                if current.state == ThreadState::Running {
+                    self.current
+                } else {
+                    self.idle
+                }
+            })
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+// 呼び出し元(kernel_main)を最初のスレッドとして登録する
+// スタックを割り当てるので memory::install の後に呼ぶ
+pub fn init() {
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "main",
+        state: ThreadState::Running,
+        saved_rsp: 0,
+        detached: true,
+        _stack: None,
+    });
+    let idle = new_thread("idle", Box::new(Box::new(idle_loop)));
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(scheduler.threads[0].is_none(), "thread::init should only be called once");
+        scheduler.threads[0] = Some(main);
+        scheduler.threads[1] = Some(idle);
+        scheduler.current = 0;
+        scheduler.idle = 1;
+    });
+}
+
+// スレッドを作って実行可能にする
+pub fn spawn<F>(name: &'static str, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    // ヒープの確保はロックの外で済ませておく
+    let thread = new_thread(name, Box::new(Box::new(f)));
+    let id = thread.id;
+    let mut reaped: [Option<Box<Thread>>; MAX_THREADS] = Default::default();
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        // join されないまま終了したスレッドをついでに回収する
+        for (slot, reaped) in reaped.iter_mut().enumerate() {
+            if matches!(&scheduler.threads[slot],
+                        Some(t) if t.detached && t.state == ThreadState::Finished) {
+                *reaped = scheduler.threads[slot].take();
+            }
+        }
+        let slot = scheduler.free_slot().expect("too many threads");
+        scheduler.threads[slot] = Some(thread);
+    });
+    // スタックの解放はロックの外で
+    drop(reaped);
+
+    JoinHandle { id }
+}
+
+// 今のスレッドの残りの時間を他のスレッドに譲る
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current;
+            scheduler.thread_mut(current).state = ThreadState::Ready;
+        }
+        schedule();
+    });
+}
+
+// 指定したティック数だけ眠る
+// ティックの長さはタイマの設定(PIT か Local APIC タイマ)で決まる
+pub fn sleep(duration: u64) {
+    let until = ticks() + duration;
+    interrupts::without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current;
+            scheduler.thread_mut(current).state = ThreadState::Sleeping { until };
+        }
+        schedule();
+    });
+}
+
+// 今のスレッドを終了する
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        let id = scheduler.thread_mut(current).id;
+        scheduler.thread_mut(current).state = ThreadState::Finished;
+        // 終了を待っているスレッドを起こす
+        for thread in scheduler.threads.iter_mut().flatten() {
+            if thread.state == ThreadState::Joining(id) {
+                thread.state = ThreadState::Ready;
+            }
+        }
+    }
+    schedule();
+    unreachable!("finished thread was scheduled again");
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current].as_ref().map(|t| t.id)
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+}
+
+// シェルなどから一覧を表示するためのスナップショット
+pub fn list() -> Vec<ThreadInfo> {
+    // Vec の確保はロックの外で
+    let mut infos = Vec::with_capacity(MAX_THREADS);
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        for thread in scheduler.threads.iter().flatten() {
+            infos.push(ThreadInfo { id: thread.id, name: thread.name, state: thread.state });
+        }
+    });
+    infos
+}
+
+// 捨てるとスレッドは切り離され、終了後に自動で回収される
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let finished = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let slot = scheduler.find(self.id)?;
+            let thread = scheduler.thread_mut(slot);
+            thread.detached = true;
+            if thread.state == ThreadState::Finished {
+                scheduler.threads[slot].take()
+            } else {
+                None
+            }
+        });
+        drop(finished);
+    }
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // スレッドの終了を待つ
+    // 終了したスレッドのスタックはここで解放される
+    pub fn join(self) {
+        // 起こされたときには終了しているはずだが、念のため確認し直す
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                let mut scheduler = SCHEDULER.lock();
+                let slot = match scheduler.find(self.id) {
+                    Some(slot) => slot,
+                    None => return None,
+                };
+                if scheduler.thread_mut(slot).state == ThreadState::Finished {
+                    return scheduler.threads[slot].take();
+                }
+                let current = scheduler.current;
+                scheduler.thread_mut(current).state = ThreadState::Joining(self.id);
+                drop(scheduler);
+                schedule();
+                None
+            });
+            // スタックの解放(ページテーブルの操作)はスケジューラのロックを外して割込みを許可してから
+            if let Some(thread) = finished {
+                drop(thread);
+                return;
+            }
+            if interrupts::without_interrupts(|| SCHEDULER.lock().find(self.id).is_none()) {
+                return;
+            }
+        }
+    }
+}
+
+// タイマ割込みのハンドラから呼ばれる
+// 眠っているスレッドを起こし、今のスレッドを横取りして次のスレッドに切り替える
+pub(crate) fn on_timer_tick() {
+    {
+        // スレッド側がロックを持っているときは、切り替えを次のティックに回す
+        let mut scheduler = match SCHEDULER.try_lock() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        if scheduler.threads[scheduler.current].is_none() {
+            // まだ init されていない
+            return;
+        }
+        scheduler.wake_sleepers(ticks());
+        let current = scheduler.current;
+        if scheduler.thread_mut(current).state == ThreadState::Running {
+            scheduler.thread_mut(current).state = ThreadState::Ready;
+        }
+    }
+    schedule();
+}
+
+// 次のスレッドに切り替える
+// 割込みを止めた状態で呼ぶこと。今のスレッドの状態は呼び出し側で更新しておく
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.wake_sleepers(ticks());
+
+        let current = scheduler.current;
+        // Ready に戻したスレッドも候補に入るよう、一度 Running に戻して選ぶ
+        let current_ready = scheduler.thread_mut(current).state == ThreadState::Ready;
+        if current_ready {
+            scheduler.thread_mut(current).state = ThreadState::Running;
+        }
+        let next = scheduler.pick_next();
+        if next == current {
+            return;
+        }
+        if current_ready {
+            scheduler.thread_mut(current).state = ThreadState::Ready;
+        }
+        scheduler.thread_mut(next).state = ThreadState::Running;
+        scheduler.current = next;
+
+        // スレッドは Box に入っているので、ロックを外してもアドレスは変わらない
+        let old_rsp: *mut u64 = &mut scheduler.thread_mut(current).saved_rsp;
+        (old_rsp, scheduler.thread_mut(next).saved_rsp)
+    };
+
+    // ロックを持ったまま切り替えると、切り替え先でロックが取れなくなる
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+fn new_thread(name: &'static str, entry: Box<Box<dyn FnOnce() + Send>>) -> Box<Thread> {
+    // スタックの名前はスレッドの名前にして、あふれたときにどのスレッドか分かるようにする
+    let stack = stack::allocate(name, STACK_SIZE).expect("failed to allocate a thread stack");
+    let stack_top = stack.top().as_u64() & !0xf;
+
+    // switch_context が pop するレジスタと ret 先を積んでおく
+    // r12 に入口の関数を入れておき、thread_trampoline から thread_start に渡す
+    // ret の直後にスタックが 16 バイト境界になるようにする(関数呼び出し規約)
+    let initial: [u64; 7] = [
+        0,                                  // r15
+        0,                                  // r14
+        0,                                  // r13
+        Box::into_raw(entry) as u64,        // r12
+        0,                                  // rbx
+        0,                                  // rbp
+        thread_trampoline as *const () as usize as u64,  // ret 先
+    ];
+    let saved_rsp = stack_top - 16 - (initial.len() * 8) as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(initial.as_ptr(), saved_rsp as *mut u64, initial.len());
+    }
+
+    Box::new(Thread {
+        id: ThreadId::new(),
+        name,
+        state: ThreadState::Ready,
+        saved_rsp,
+        detached: false,
+        _stack: Some(stack),
+    })
+}
+
+fn idle_loop() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// 新しいスレッドが最初に切り替えられたときに入ってくる
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    // タイマ割込みか yield_now の中(割込み禁止)から切り替わってくるので、ここで許可する
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit();
+}
+
+extern "C" {
+    // 今のスタックポインタを *old_rsp に保存し、new_rsp のスタックに切り替える
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+core::arch::global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {thread_start}",
+    "ud2",
+    thread_start = sym thread_start,
+);
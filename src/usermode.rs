@@ -0,0 +1,426 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, TranslateResult}, FrameAllocator, Page, PageTable, PageTableFlags,
+        Size4KiB, Translate,
+    },
+};
+use crate::elf::{ElfError, ElfFile};
+use crate::memory::{self, AddressSpace, buddy::BuddyAllocator};
+use crate::syscall;
+
+// リング 3 でユーザプログラムを動かす
+// ユーザプログラムは専用のアドレス空間を作り、USER_ACCESSIBLE を付けたページにコピーして実行するので、
+// それ以外のページ(カーネル)に触るとページフォルトになり、プログラムは強制終了される
+//
+// カーネルに戻る先は1つしか覚えておけないので、同時に動かせるユーザプログラムは1つだけ
+
+// ユーザプログラムのコードとスタックを置く仮想アドレス
+const USER_CODE_ADDR: u64 = 0x0000_0800_0000_0000;
+const USER_STACK_ADDR: u64 = 0x0000_0800_0010_0000;
+const USER_STACK_SIZE: u64 = 4096 * 4;
+const MAX_PROGRAM_SIZE: usize = 4096 * 16;
+
+// 下半分(ユーザ空間)の上限
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static KILLED: AtomicBool = AtomicBool::new(false);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// usermode_enter を呼んだときのカーネルのスタックポインタ
+// usermode_return でここに戻る
+static mut KERNEL_RETURN_RSP: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    // exit システムコールで終了した
+    Exited(u64),
+    // 例外を起こしたので強制終了した
+    Killed,
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    // 他のユーザプログラムが動いている
+    Busy,
+    ProgramTooLarge,
+    Elf(ElfError),
+    // ユーザ空間の外にロードしようとした
+    InvalidAddress(u64),
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for UserError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        UserError::Map(error)
+    }
+}
+
+impl From<ElfError> for UserError {
+    fn from(error: ElfError) -> Self {
+        UserError::Elf(error)
+    }
+}
+
+// gdt::init と memory::init の後に呼ぶ
+pub fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    syscall::init();
+}
+
+// カーネルに埋め込んだ ELF 形式のユーザプログラム(ソースは user/hello.s)
+pub const HELLO_ELF: &[u8] = include_bytes!("../user/hello.elf");
+// 同じプログラムを、ファイルの中でセグメントを詰めてリンクしたもの
+pub const HELLO_PACKED_ELF: &[u8] = include_bytes!("../user/hello_packed.elf");
+// 権限の違うセグメントが同じページを共有するようにリンクしたもの(ロードできない)
+pub const HELLO_SHARED_ELF: &[u8] = include_bytes!("../user/hello_shared.elf");
+
+#[derive(Debug, Clone, Copy)]
+pub enum Program {
+    // USER_CODE_ADDR に置いて先頭から実行する位置独立な機械語
+    Raw(&'static [u8]),
+    // ELF64 の実行ファイル
+    Elf(&'static [u8]),
+}
+
+// 組み込みのユーザプログラムを名前で探す
+pub fn program(name: &str) -> Option<Program> {
+    extern "C" {
+        static user_hello_start: u8;
+        static user_hello_end: u8;
+        static user_badwrite_start: u8;
+        static user_badwrite_end: u8;
+        static user_peek_start: u8;
+        static user_peek_end: u8;
+    }
+
+    unsafe fn bytes(start: &'static u8, end: &'static u8) -> &'static [u8] {
+        let start = start as *const u8;
+        core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+    }
+
+    unsafe {
+        match name {
+            "hello" => Some(Program::Raw(bytes(&user_hello_start, &user_hello_end))),
+            "badwrite" => Some(Program::Raw(bytes(&user_badwrite_start, &user_badwrite_end))),
+            "peek" => Some(Program::Raw(bytes(&user_peek_start, &user_peek_end))),
+            "hello-elf" => Some(Program::Elf(HELLO_ELF)),
+            "hello-packed" => Some(Program::Elf(HELLO_PACKED_ELF)),
+            _ => None,
+        }
+    }
+}
+
+pub const PROGRAMS: &[&str] = &["hello", "badwrite", "peek", "hello-elf", "hello-packed"];
+
+// program を新しいアドレス空間にロードしてリング 3 で実行し、終了するまで待つ
+// フレームは memory::install で共有されたアロケータから取る
+// 実行中はロックを離しておき、その間もヒープを広げられるようにする
+pub fn run(program: Program) -> Result<ExitStatus, UserError> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(UserError::Busy);
+    }
+
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let loaded = with_frame_allocator(|frame_allocator| {
+        let mut space = AddressSpace::new(offset, frame_allocator)
+            .ok_or(UserError::Map(MapToError::FrameAllocationFailed))?;
+        match load(&mut space, program, frame_allocator) {
+            Ok(entry) => Ok((space, entry)),
+            Err(error) => {
+                // ロードに失敗したときも、途中までマップしたフレームを返す
+                unsafe { space.free(frame_allocator) };
+                Err(error)
+            }
+        }
+    });
+    let result = loaded.map(|(space, entry)| {
+        let status = enter(&space, entry);
+        with_frame_allocator(|frame_allocator| unsafe { space.free(frame_allocator) });
+        status
+    });
+
+    RUNNING.store(false, Ordering::Release);
+    result
+}
+
+fn with_frame_allocator<R>(f: impl FnOnce(&mut BuddyAllocator) -> R) -> R {
+    let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    f(frame_allocator.as_mut().expect("memory::install has not been called"))
+}
+
+fn load(
+    space: &mut AddressSpace,
+    program: Program,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, UserError> {
+    let entry = match program {
+        Program::Raw(code) => load_raw(space, code, frame_allocator)?,
+        Program::Elf(binary) => load_elf(space, binary, frame_allocator)?,
+    };
+    let stack_flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_user_region(VirtAddr::new(USER_STACK_ADDR), USER_STACK_SIZE, stack_flags, frame_allocator)?;
+    Ok(entry)
+}
+
+fn enter(space: &AddressSpace, entry: u64) -> ExitStatus {
+    KILLED.store(false, Ordering::Relaxed);
+    let (kernel_table, flags) = Cr3::read();
+    let stack_top = USER_STACK_ADDR + USER_STACK_SIZE;
+    // sysret で IF が立つまで割込みを止めておき、戻ってきたら元に戻す
+    let code = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        let code = usermode_enter(entry, stack_top);
+        Cr3::write(kernel_table, flags);
+        code
+    });
+    if KILLED.load(Ordering::Relaxed) {
+        ExitStatus::Killed
+    } else {
+        ExitStatus::Exited(code)
+    }
+}
+
+// exit システムコールから呼ばれ、usermode_enter の呼び出し元に戻る
+pub(crate) fn exit(code: u64) -> ! {
+    unsafe { usermode_return(code) }
+}
+
+// ユーザモードで例外が起きたときに例外ハンドラから呼ばれる
+pub(crate) fn kill() -> ! {
+    assert!(RUNNING.load(Ordering::Relaxed), "no user program is running");
+    KILLED.store(true, Ordering::Relaxed);
+    unsafe { usermode_return(0) }
+}
+
+// [addr, addr + len) がすべてユーザからアクセスできるページか
+// システムコールでユーザから渡されたポインタを使う前に確認する
+pub fn is_user_range(addr: u64, len: u64) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    let mut page = addr & !0xfff;
+    while page < end {
+        if !is_user_page(VirtAddr::new(page)) {
+            return false;
+        }
+        page += 4096;
+    }
+    true
+}
+
+// ページテーブルを上から辿り、すべての階層で PRESENT と USER_ACCESSIBLE が立っているか確認する
+fn is_user_page(addr: VirtAddr) -> bool {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        let table = unsafe { &*((offset + table_addr.as_u64()) as *const PageTable) };
+        let entry = &table[index];
+        if !entry.flags().contains(required) {
+            return false;
+        }
+        // L3, L2 のエントリはヒュージページを指していることがある
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table_addr = entry.addr();
+    }
+    true
+}
+
+fn load_raw(
+    space: &mut AddressSpace,
+    code: &[u8],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, UserError> {
+    if code.len() > MAX_PROGRAM_SIZE {
+        return Err(UserError::ProgramTooLarge);
+    }
+
+    // コードは書き換えられないようにする
+    let code_addr = VirtAddr::new(USER_CODE_ADDR);
+    space.map_user_region(code_addr, code.len() as u64, PageTableFlags::empty(), frame_allocator)?;
+    space.write(code_addr, code).expect("user code is not mapped");
+    Ok(USER_CODE_ADDR)
+}
+
+// PT_LOAD のセグメントを p_flags に合わせた権限でマップする
+// 書き込みできるのは PF_W のセグメントだけで、PF_X 以外は実行できない(NX)
+fn load_elf(
+    space: &mut AddressSpace,
+    binary: &[u8],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, UserError> {
+    let elf = ElfFile::parse(binary)?;
+    // sysret で非正規なアドレスに戻ろうとすると、リング 0 のまま #GP が起きてしまう
+    if elf.entry() >= USER_SPACE_END {
+        return Err(UserError::InvalidAddress(elf.entry()));
+    }
+
+    for header in elf.program_headers().filter(|h| h.is_load() && h.p_memsz > 0) {
+        let start = header.p_vaddr;
+        match start.checked_add(header.p_memsz) {
+            Some(end) if end <= USER_SPACE_END => {}
+            _ => return Err(UserError::InvalidAddress(start)),
+        }
+        // カーネルと共有しているページテーブルに USER_ACCESSIBLE が付かないようにする
+        if space.overlaps_kernel(VirtAddr::new(start), header.p_memsz) {
+            return Err(UserError::InvalidAddress(start));
+        }
+
+        let mut flags = PageTableFlags::empty();
+        if header.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !header.is_executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        map_segment(space, VirtAddr::new(start), header.p_memsz, flags, frame_allocator)?;
+        space.write(VirtAddr::new(start), elf.segment_data(&header))
+            .expect("segment is not mapped");
+    }
+    Ok(elf.entry())
+}
+
+// セグメントのページをマップする
+// 前のセグメントと同じページに入っている部分(境界がページにそろっていないとき)はもうマップされている
+// 権限が同じならそのまま使い、違えば W^X を崩さないようにロードを断る
+fn map_segment(
+    space: &mut AddressSpace,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), UserError> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::containing_address(start + size - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        let mapper = space.mapper();
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags: current, .. } => {
+                let permissions = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                if current & permissions != flags & permissions {
+                    return Err(UserError::InvalidAddress(page.start_address().as_u64()));
+                }
+            }
+            _ => space.map_user_region(page.start_address(), 4096, flags, frame_allocator)?,
+        }
+    }
+    Ok(())
+}
+
+extern "C" {
+    // ユーザプログラムに入り、usermode_return が呼ばれるとその値を返す
+    fn usermode_enter(entry: u64, user_rsp: u64) -> u64;
+    fn usermode_return(value: u64) -> !;
+}
+
+// usermode_enter は setjmp、usermode_return は longjmp のようなもの
+// usermode_return はシステムコールや例外ハンドラのスタックを捨てて、usermode_enter の呼び出し元に戻る
+core::arch::global_asm!(
+    ".global usermode_enter",
+    "usermode_enter:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rip + {kernel_rsp}], rsp",
+    // sysret は rcx を rip に、r11 を rflags にする(0x202 は IF と予約ビット)
+    "mov rcx, rdi",
+    "mov rsp, rsi",
+    "mov r11, 0x202",
+    // カーネルの値がユーザに見えないようにレジスタを消しておく
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "sysretq",
+    "",
+    ".global usermode_return",
+    "usermode_return:",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "mov rax, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    kernel_rsp = sym KERNEL_RETURN_RSP,
+);
+
+// 組み込みのユーザプログラム
+// 実行時にはコピーして別のアドレスに置くので、rip 相対のアドレッシングだけを使う
+core::arch::global_asm!(
+    ".pushsection .rodata.user_programs, \"a\"",
+
+    // メッセージを表示して終了する
+    ".global user_hello_start",
+    ".global user_hello_end",
+    "user_hello_start:",
+    "mov eax, {write}",
+    "lea rdi, [rip + 2f]",
+    "lea rsi, [rip + 3f]",
+    "sub rsi, rdi",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    "2:",
+    ".ascii \"hello from ring 3!\\n\"",
+    "3:",
+    "user_hello_end:",
+
+    // カーネルのアドレスを write に渡し、その戻り値を終了コードにする
+    ".global user_badwrite_start",
+    ".global user_badwrite_end",
+    "user_badwrite_start:",
+    "mov eax, {write}",
+    "mov edi, 0x200000",
+    "mov esi, 16",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    "ud2",
+    "user_badwrite_end:",
+
+    // カーネルのメモリを直接読もうとする(ページフォルトで強制終了される)
+    ".global user_peek_start",
+    ".global user_peek_end",
+    "user_peek_start:",
+    "mov eax, 0x200000",
+    "mov rdi, [rax]",
+    "mov eax, {exit}",
+    "syscall",
+    "ud2",
+    "user_peek_end:",
+
+    ".popsection",
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+);
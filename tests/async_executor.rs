@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use bootloader::{BootInfo, entry_point};
+use core::cell::Cell;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use blog_os::task::{Task, executor::Executor, simple_executor::SimpleExecutor};
+use blog_os::{interrupts, thread};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+    // 別のスレッドからタスクを起こすテストのため、スレッドを使えるようにする
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn runs_all_tasks() {
+    let counter = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..3 {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            counter.set(counter.get() + 1);
+        }));
+    }
+    executor.run();
+    assert_eq!(counter.get(), 3);
+}
+
+// 1回目のポーリングでは自分を起こして Pending を返す
+struct YieldOnce {
+    yielded: bool,
+}
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn resumes_pending_task() {
+    let done = Rc::new(Cell::new(false));
+    let mut executor = SimpleExecutor::new();
+    let flag = done.clone();
+    executor.spawn(Task::new(async move {
+        YieldOnce { yielded: false }.await;
+        flag.set(true);
+    }));
+    executor.run();
+    assert!(done.get());
+}
+
+// Waker で起こされたタスクが、もう一度ポーリングされて終わる
+#[test_case]
+fn executor_resumes_woken_task() {
+    let done = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        let done = done.clone();
+        executor.spawn(Task::new(async move {
+            YieldOnce { yielded: false }.await;
+            done.set(done.get() + 1);
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(done.get(), 3);
+}
+
+// 別のスレッドが起こすまで Pending を返し続ける
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+struct WaitForWake;
+
+impl Future for WaitForWake {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // 起こす側がフラグを立てる前に Waker を置いておく
+        *WAKER.lock() = Some(cx.waker().clone());
+        if WOKEN.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// 実行できるタスクがないときは hlt で眠り、割込みの後に起こされたタスクを実行する
+#[test_case]
+fn executor_sleeps_until_woken() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WaitForWake));
+    let waker = thread::spawn("waker", || {
+        thread::sleep(2);
+        WOKEN.store(true, Ordering::SeqCst);
+        if let Some(waker) = WAKER.lock().take() {
+            waker.wake();
+        }
+    });
+
+    let start = interrupts::ticks();
+    executor.run_until_complete();
+    // 起こされるまでの間、タイマ割込みをまたいで眠っていた
+    assert!(interrupts::ticks() > start);
+    assert!(WOKEN.load(Ordering::SeqCst));
+    waker.join();
+}
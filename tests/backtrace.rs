@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use blog_os::{backtrace, memory};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    memory::set_physical_memory_offset(phys_mem_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    backtrace::init(&boot_info.memory_map, phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn named_function() {}
+
+// ブートローダが読み込んだカーネルの ELF から、関数の名前を引ける
+#[test_case]
+fn lookup_own_function() {
+    let addr = named_function as *const () as u64;
+    let (symbol, offset) = backtrace::lookup(addr).expect("no symbol for the test function");
+    assert_eq!(offset, 0);
+    assert!(symbol.name.contains("named_function"));
+}
+
+#[inline(never)]
+fn innermost(callers: &mut [u64; 4]) {
+    let mut depth = 0;
+    backtrace::walk(backtrace::frame_pointer(), |return_address| {
+        callers[depth] = return_address;
+        depth += 1;
+        depth < callers.len()
+    });
+}
+
+#[inline(never)]
+fn outer(callers: &mut [u64; 4]) {
+    innermost(callers);
+}
+
+#[inline(never)]
+fn outermost(callers: &mut [u64; 4]) {
+    outer(callers);
+}
+
+// 戻りアドレスが呼び出した関数の中を指している
+#[test_case]
+fn walk_reaches_callers() {
+    let mut callers = [0; 4];
+    outermost(&mut callers);
+    let name = |addr: u64| backtrace::lookup(addr - 1).map(|(symbol, _)| symbol.name);
+    assert!(name(callers[0]).is_some_and(|name| demangles_to(name, "backtrace::outer")));
+    assert!(name(callers[1]).is_some_and(|name| demangles_to(name, "backtrace::outermost")));
+}
+
+// rbp がマップされていないアドレスを指していても、読まずに止まる
+#[test_case]
+fn walk_stops_at_unmapped_frame() {
+    let mut frames = 0;
+    backtrace::walk(0x_7fff_ffff_0000, |_| {
+        frames += 1;
+        true
+    });
+    assert_eq!(frames, 0);
+}
+
+// 表示した名前が expected とちょうど同じか
+fn demangles_to(symbol: &str, expected: &str) -> bool {
+    // 書かれた文字列を expected の先頭から順に突き合わせる
+    struct Compare<'a> {
+        rest: &'a str,
+    }
+
+    impl Write for Compare<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.rest = self.rest.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut compare = Compare { rest: expected };
+    write!(compare, "{}", backtrace::Demangle(symbol)).is_ok() && compare.rest.is_empty()
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use blog_os::memory::BootInfoFrameAllocatior;
+use blog_os::memory::buddy::{BuddyAllocator, ORDER_2MIB};
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocatior::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = frame_allocator.into_buddy();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocates_aligned_blocks() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    for order in 0..=4 {
+        let block = allocator.allocate(order).expect("out of memory");
+        assert!(block.is_aligned(4096u64 << order));
+        unsafe { allocator.deallocate(block, order) };
+    }
+}
+
+// 小さいブロックを全部返すと、元の大きなブロックにまとめ直される
+#[test_case]
+fn freed_blocks_are_coalesced() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let before = allocator.free_frames();
+    // 空いている order 0 のブロックがあるとそちらから取られるので、8 フレームは 1 つのブロックから取る
+    let first = allocator.allocate(3).expect("no order 3 block");
+    let frames: [_; 8] = core::array::from_fn(|i| {
+        PhysFrame::<Size4KiB>::containing_address(first + i as u64 * 4096)
+    });
+    assert_eq!(allocator.free_frames(), before - 8);
+    for frame in frames {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.free_frames(), before);
+
+    // まとめ直されていれば、最初のフレームから始まる 8 フレームのブロックがまた取れる
+    // (まとめ直されなければ 8 個の order 0 のブロックのままなので、別のブロックが返る)
+    let block = allocator.allocate(3).expect("no order 3 block");
+    assert_eq!(block, first);
+    unsafe { allocator.deallocate(block, 3) };
+}
+
+// 大きなブロックを小さく分けて返しても、全部返ればまとめ直される
+#[test_case]
+fn part_of_block_can_be_freed() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let before = allocator.free_frames();
+    let large = allocator.allocate(ORDER_2MIB).expect("no 2MiB block");
+
+    // 先頭の 1 フレームと、残りを order 0 のまま返す
+    unsafe { allocator.deallocate(large, 0) };
+    assert_eq!(allocator.free_frames(), before - 511);
+    for i in 1..512u64 {
+        unsafe { allocator.deallocate(large + i * 4096, 0) };
+    }
+    assert_eq!(allocator.free_frames(), before);
+
+    let again = allocator.allocate(ORDER_2MIB).expect("no 2MiB block");
+    assert_eq!(again, large);
+    unsafe { allocator.deallocate(again, ORDER_2MIB) };
+}
+
+#[test_case]
+fn allocates_2mib_frames() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no 2MiB frame");
+    assert!(frame.start_address().is_aligned(2u64 * 1024 * 1024));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn order_for_size() {
+    assert_eq!(BuddyAllocator::order_for_size(1), 0);
+    assert_eq!(BuddyAllocator::order_for_size(4096), 0);
+    assert_eq!(BuddyAllocator::order_for_size(4097), 1);
+    assert_eq!(BuddyAllocator::order_for_size(64 * 1024), 4);
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use blog_os::memory::BootInfoFrameAllocatior;
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocatior>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocatior::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocates_distinct_frames() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let before = allocator.free_frames();
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(allocator.free_frames(), before - 2);
+    unsafe {
+        allocator.deallocate_frame(second);
+        allocator.deallocate_frame(first);
+    }
+    assert_eq!(allocator.free_frames(), before);
+}
+
+#[test_case]
+fn reuses_freed_frames() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+}
+
+// 以前は割り当てのたびにメモリマップを先頭から数え直していたので、数が多いと極端に遅かった
+#[test_case]
+fn many_allocations() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let first = allocator.allocate_frame().unwrap();
+    let mut last = first;
+    for _ in 0..10_000 {
+        last = allocator.allocate_frame().unwrap();
+    }
+    assert_ne!(first, last);
+}
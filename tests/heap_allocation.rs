@@ -12,18 +12,8 @@ use core::panic::PanicInfo;
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use blog_os::allocator;
-    use blog_os::memory::{self, BootInfoFrameAllocatior};
-    use x86_64::VirtAddr;
-
     blog_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocatior::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    blog_os::test_init_memory(boot_info);
 
     test_main();
     loop {}
@@ -66,3 +56,118 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+// 長く生きている割り当てがあっても、解放された領域を再利用できること
+// バンプアロケータは全部解放されるまで再利用できないので失敗する
+#[test_case]
+#[cfg(not(feature = "heap_bump"))]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+use blog_os::allocator::heap_size;
+
+// 最初にマップした HEAP_SIZE より大きな割り当ては、ヒープを広げて成功する
+#[test_case]
+fn grows_beyond_initial_size() {
+    let n = HEAP_SIZE / 8 * 2;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec[n - 1], n - 1);
+    assert!(heap_size() > HEAP_SIZE);
+}
+
+use blog_os::memory::inspect::{self, PageSize};
+
+// 小さな割り当てを重ねて大きくなったヒープも、2MiB を超えた先は 2MiB のページでマップされる
+#[test_case]
+fn large_heap_uses_huge_pages() {
+    const MIB: usize = 1024 * 1024;
+    let mut blocks = Vec::new();
+    while heap_size() < 6 * MIB {
+        blocks.push(alloc::vec![0u8; 64 * 1024]);
+    }
+    let last = allocator::heap_start() + heap_size() - 1;
+    let translation = inspect::translate(x86_64::VirtAddr::new(last as u64)).expect("heap is not mapped");
+    assert_eq!(translation.page_size, PageSize::Size2MiB);
+}
+
+use blog_os::allocator::Locked;
+use x86_64::instructions::interrupts;
+
+// ロックを持っている間は割込みが止まり、解放すると元に戻る
+#[test_case]
+fn lock_disables_interrupts() {
+    let locked = Locked::new(0);
+    let were_enabled = interrupts::are_enabled();
+    {
+        let mut value = locked.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert_eq!(interrupts::are_enabled(), were_enabled);
+    assert_eq!(*locked.lock(), 1);
+}
+
+// 割込みを止めた中(割込みハンドラと同じ状況)でもアロケートでき、割込みは止まったまま
+#[test_case]
+fn allocation_with_interrupts_disabled() {
+    interrupts::without_interrupts(|| {
+        let x = Box::new(42);
+        assert_eq!(*x, 42);
+        assert!(!interrupts::are_enabled());
+    });
+}
+
+use blog_os::allocator;
+
+// 割り当てをすべて解放すれば、使用中のバイト数と割り当ての数は元に戻る
+#[test_case]
+fn stats_balance_after_free() {
+    let before = allocator::stats();
+    {
+        let boxes: Vec<Box<u64>> = (0..10).map(Box::new).collect();
+        let during = allocator::stats();
+        assert!(during.bytes_in_use >= before.bytes_in_use + 10 * 8);
+        assert!(during.peak_bytes >= during.bytes_in_use);
+        assert_eq!(boxes.len(), 10);
+    }
+    let after = allocator::stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+    assert!(after.allocations >= before.allocations + 11);
+}
+
+#[cfg(feature = "heap_leak_tracking")]
+use blog_os::allocator::leak;
+
+#[test_case]
+#[cfg(feature = "heap_leak_tracking")]
+fn no_leaks() {
+    let mark = leak::mark();
+    let mut vec = Vec::new();
+    for i in 0..100 {
+        vec.push(Box::new(i));
+    }
+    drop(vec);
+    leak::report_leaks_since(mark);
+    assert_eq!(leak::leaks_since(mark), 0);
+}
+
+// わざとリークさせた割り当ては記録に残る
+#[test_case]
+#[cfg(feature = "heap_leak_tracking")]
+fn leak_is_detected() {
+    let mark = leak::mark();
+    let leaked: &mut u64 = Box::leak(Box::new(7));
+    assert_eq!(leak::leaks_since(mark), 1);
+    unsafe { drop(Box::from_raw(leaked)) };
+    assert_eq!(leak::leaks_since(mark), 0);
+}
@@ -0,0 +1,203 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB};
+use blog_os::memory;
+use blog_os::memory::inspect::{self, PageSize};
+use blog_os::memory::mapping;
+
+entry_point!(main);
+
+// どこにも使われていない仮想アドレス(1GiB にそろっている)
+const WINDOW_START: u64 = 0x_7777_0000_0000;
+const ANONYMOUS_START: u64 = 0x_7777_4000_0000;
+const PROTECT_START: u64 = 0x_7777_8000_0000;
+const UNMAP_START: u64 = 0x_7777_c000_0000;
+// 上の段のテーブルも新しく作られるように、L4 のエントリを分ける
+const READ_ONLY_START: u64 = 0x_7780_0000_0000;
+const MIB: u64 = 1024 * 1024;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn page_size(addr: u64) -> PageSize {
+    inspect::translate(VirtAddr::new(addr)).expect("address is not mapped").page_size
+}
+
+// 物理メモリの窓は、そろっている部分が 2MiB のページになり、端は 4KiB のページになる
+#[test_case]
+fn map_range_uses_huge_pages() {
+    let size = 4 * MIB + 8 * 1024;
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapping::map_range(
+                mapper.as_mut().unwrap(), VirtAddr::new(WINDOW_START), PhysAddr::new(0), size,
+                flags, frame_allocator.as_mut().unwrap(),
+            )
+        }.expect("map_range failed");
+    }
+
+    assert_eq!(page_size(WINDOW_START), PageSize::Size2MiB);
+    assert_eq!(page_size(WINDOW_START + 2 * MIB), PageSize::Size2MiB);
+    assert_eq!(page_size(WINDOW_START + 4 * MIB), PageSize::Size4KiB);
+    // 窓から読んだ値が、物理メモリのマッピングから読んだ値と同じ
+    for &offset in [0xb8000, 3 * MIB + 8, 4 * MIB + 4096].iter() {
+        let through_window = unsafe { *((WINDOW_START + offset) as *const u64) };
+        let direct = unsafe { *(memory::physical_memory_offset() + offset).as_ptr::<u64>() };
+        assert_eq!(through_window, direct);
+    }
+}
+
+// 新しく割り当てる領域も 2MiB のページでマップされ、書き込める
+#[test_case]
+fn map_anonymous_uses_huge_pages() {
+    let mapped = {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        mapping::map_anonymous(
+            mapper.as_mut().unwrap(), VirtAddr::new(ANONYMOUS_START), 4 * MIB,
+            flags, frame_allocator.as_mut().unwrap(),
+        ).expect("map_anonymous failed")
+    };
+    assert_eq!(mapped, 4 * MIB);
+    assert_eq!(page_size(ANONYMOUS_START), PageSize::Size2MiB);
+
+    let last = (ANONYMOUS_START + 4 * MIB - 8) as *mut u64;
+    unsafe { last.write_volatile(0x1234) };
+    assert_eq!(unsafe { last.read_volatile() }, 0x1234);
+}
+
+// 大きなページの一部だけ権限を変えると、そのページが分けられて残りは元の権限のまま
+#[test_case]
+fn protect_splits_huge_page() {
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        mapping::map_anonymous(
+            mapper.as_mut().unwrap(), VirtAddr::new(PROTECT_START), 4 * MIB,
+            flags, frame_allocator.as_mut().unwrap(),
+        ).expect("map_anonymous failed");
+    }
+    assert_eq!(page_size(PROTECT_START + 2 * MIB), PageSize::Size2MiB);
+
+    let target = PROTECT_START + 2 * MIB + 8 * 4096;
+    let before = inspect::translate(VirtAddr::new(target)).unwrap();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        mapping::protect(
+            mapper.as_mut().unwrap(), VirtAddr::new(target), 4096,
+            flags, frame_allocator.as_mut().unwrap(),
+        ).expect("protect failed");
+    }
+
+    let after = inspect::translate(VirtAddr::new(target)).unwrap();
+    assert_eq!(after.page_size, PageSize::Size4KiB);
+    assert_eq!(after.level, 1);
+    assert_eq!(after.phys, before.phys);
+    assert!(!after.flags.contains(PageTableFlags::WRITABLE));
+    assert!(after.flags.contains(PageTableFlags::NO_EXECUTE));
+
+    let neighbor = inspect::translate(VirtAddr::new(target + 4096)).unwrap();
+    assert_eq!(neighbor.page_size, PageSize::Size4KiB);
+    assert!(neighbor.flags.contains(PageTableFlags::WRITABLE));
+    // 隣の 2MiB のページは分けられていない
+    assert_eq!(page_size(PROTECT_START), PageSize::Size2MiB);
+}
+
+// 読み込み専用でマップした範囲を書き込めるようにすると、上の段のエントリも書き込めるようになる
+#[test_case]
+fn protect_makes_read_only_mapping_writable() {
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().expect("no free frame");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapping::map_range(
+                mapper.as_mut().unwrap(), VirtAddr::new(READ_ONLY_START), frame.start_address(), 4096,
+                flags, frame_allocator,
+            )
+        }.expect("map_range failed");
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        mapping::protect(
+            mapper.as_mut().unwrap(), VirtAddr::new(READ_ONLY_START), 4096,
+            flags, frame_allocator,
+        ).expect("protect failed");
+    }
+
+    let value = READ_ONLY_START as *mut u64;
+    unsafe { value.write_volatile(0x9abc) };
+    assert_eq!(unsafe { value.read_volatile() }, 0x9abc);
+}
+
+// 大きなページの一部だけ外すと、そのページを分けて残りはマップしたままにする
+#[test_case]
+fn unmap_range_splits_huge_page() {
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        mapping::map_anonymous(
+            mapper.as_mut().unwrap(), VirtAddr::new(UNMAP_START), 4 * MIB,
+            flags, frame_allocator.as_mut().unwrap(),
+        ).expect("map_anonymous failed");
+    }
+    assert_eq!(page_size(UNMAP_START + 2 * MIB), PageSize::Size2MiB);
+
+    let target = UNMAP_START + 2 * MIB + 8 * 4096;
+    let neighbor_before = inspect::translate(VirtAddr::new(target + 4096)).unwrap();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        mapping::unmap_range(
+            mapper.as_mut().unwrap(), VirtAddr::new(target), 4096,
+            frame_allocator.as_mut().unwrap(), true,
+        ).expect("unmap_range failed");
+    }
+
+    assert!(inspect::translate(VirtAddr::new(target)).is_err());
+    let neighbor = inspect::translate(VirtAddr::new(target + 4096)).unwrap();
+    assert_eq!(neighbor.page_size, PageSize::Size4KiB);
+    assert_eq!(neighbor.phys, neighbor_before.phys);
+    let value = (target + 4096) as *mut u64;
+    unsafe { value.write_volatile(0x5678) };
+    assert_eq!(unsafe { value.read_volatile() }, 0x5678);
+    // 隣の 2MiB のページは分けられていない
+    assert_eq!(page_size(UNMAP_START), PageSize::Size2MiB);
+}
+
+#[test_case]
+fn protect_unmapped_fails() {
+    let mut mapper = memory::MAPPER.lock();
+    let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    let result = mapping::protect(
+        mapper.as_mut().unwrap(), VirtAddr::new(ANONYMOUS_START + 8 * MIB), 4096,
+        PageTableFlags::PRESENT, frame_allocator.as_mut().unwrap(),
+    );
+    assert_eq!(result, Err(mapping::ProtectError::NotMapped(VirtAddr::new(ANONYMOUS_START + 8 * MIB))));
+}
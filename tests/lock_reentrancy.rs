@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use blog_os::{QemuExitCode, exit_qemu, serial_println, serial_print};
+use blog_os::allocator::Locked;
+
+// 1回しか panic できないのでハーネスを無効化
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("lock_reentrancy::reentrant_lock_panics...\t");
+    reentrant_lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// 同じロックをもう一度取ろうとしたら、待ち続けずに panic で知らせる
+fn reentrant_lock() {
+    let locked = Locked::new(0);
+    let _outer = locked.lock();
+    let _inner = locked.lock();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::Translate;
+use blog_os::memory;
+use blog_os::memory::fault::{self, FaultRegionError};
+
+entry_point!(main);
+
+// どこにも使われていない仮想アドレス
+const REGION_START: u64 = 0x_6666_0000_0000;
+const REGION_SIZE: u64 = 16 * 4096;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: u64) -> bool {
+    memory::MAPPER.lock().as_ref().unwrap().translate_addr(VirtAddr::new(addr)).is_some()
+}
+
+// 触ったページだけが、0 で埋めたフレームでマップされる
+#[test_case]
+fn demand_zero_maps_on_first_touch() {
+    let start = VirtAddr::new(REGION_START);
+    fault::register("test", start, REGION_SIZE, fault::demand_zero).expect("failed to register");
+    assert_eq!(fault::region_name(start + 4096u64), Some("test"));
+    assert!(!is_mapped(REGION_START + 4096));
+
+    let ptr = (REGION_START + 4096 + 8) as *mut u64;
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(REGION_START + 4096));
+    assert!(!is_mapped(REGION_START + 2 * 4096));
+
+    fault::unregister(start);
+    assert_eq!(fault::region_name(start), None);
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    let start = VirtAddr::new(REGION_START + REGION_SIZE);
+    fault::register("first", start, REGION_SIZE, fault::demand_zero).unwrap();
+    assert_eq!(
+        fault::register("second", start + 4096u64, REGION_SIZE, fault::demand_zero),
+        Err(FaultRegionError::Overlap)
+    );
+    fault::unregister(start);
+}
+
+// ユーザモードからのアクセスや保護違反は解決しない
+#[test_case]
+fn demand_zero_rejects_protection_violations() {
+    let addr = VirtAddr::new(REGION_START);
+    assert!(!fault::demand_zero(addr, PageFaultErrorCode::PROTECTION_VIOLATION));
+    assert!(!fault::demand_zero(addr, PageFaultErrorCode::USER_MODE));
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use blog_os::memory;
+use blog_os::memory::inspect::{self, NotMapped, PageSize};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// アドレスを調べるためのカーネルのコード
+#[inline(never)]
+fn code() {}
+
+// x86_64 クレートの変換と同じ物理アドレスになる
+#[test_case]
+fn translate_matches_mapper() {
+    let local = 0u64;
+    let addrs = [
+        VirtAddr::from_ptr(&local),
+        VirtAddr::new(code as *const () as u64),
+        memory::physical_memory_offset() + 0xb8000u64,
+    ];
+    for &addr in addrs.iter() {
+        let expected = memory::MAPPER.lock().as_ref().unwrap().translate_addr(addr);
+        let translation = inspect::translate(addr).expect("address is not mapped");
+        assert_eq!(Some(translation.phys), expected);
+    }
+}
+
+// ブートローダは物理メモリ全体を 2MiB のページでマップしているので、L2 のエントリで変換が決まる
+#[test_case]
+fn translate_reports_level() {
+    let translation = inspect::translate(memory::physical_memory_offset() + 0x20_0008u64).unwrap();
+    assert_eq!(translation.phys, PhysAddr::new(0x20_0008));
+    assert_eq!(translation.page_size, PageSize::Size2MiB);
+    assert_eq!(translation.level, 2);
+}
+
+#[test_case]
+fn unmapped_address() {
+    assert!(matches!(inspect::translate(VirtAddr::new(0)), Err(NotMapped { .. })));
+}
+
+// スタックは書き込めて、カーネルのコードは書き込めない
+#[test_case]
+fn effective_permissions() {
+    let local = 0u64;
+    let stack = inspect::translate(VirtAddr::from_ptr(&local)).unwrap();
+    assert!(stack.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!stack.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+
+    let code = inspect::translate(VirtAddr::new(code as *const () as u64)).unwrap();
+    assert!(!code.flags.contains(PageTableFlags::WRITABLE));
+}
+
+// 範囲は重ならずにアドレス順に並び、マップされているアドレスはどれかの範囲に入っている
+#[test_case]
+fn ranges_cover_mappings() {
+    let local = 0u64;
+    let target = VirtAddr::from_ptr(&local);
+    let mut previous: Option<VirtAddr> = None;
+    let mut covered = false;
+    inspect::for_each_range(|range| {
+        if let Some(last) = previous {
+            assert!(last < range.start);
+        }
+        previous = Some(range.last());
+        if range.start <= target && target <= range.last() {
+            let translation = inspect::translate(target).unwrap();
+            assert_eq!(translation.phys, range.phys_start + (target - range.start));
+            covered = true;
+        }
+    });
+    assert!(covered);
+}
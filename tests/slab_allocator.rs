@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::ptr::NonNull;
+use blog_os::memory;
+use blog_os::memory::slab::SlabCache;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> u64 {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+#[test_case]
+fn alloc_and_free() {
+    let mut cache = SlabCache::new("u64");
+    let a = cache.alloc(1u64).expect("out of memory");
+    let b = cache.alloc(2u64).expect("out of memory");
+    assert_ne!(a, b);
+    unsafe {
+        assert_eq!(*a.as_ref(), 1);
+        assert_eq!(*b.as_ref(), 2);
+        cache.free(a);
+        cache.free(b);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.allocations, 2);
+    assert_eq!(stats.frees, 2);
+}
+
+// 1つのスラブに入りきらない数を割り当てると、新しいスラブが使われる
+#[test_case]
+fn grows_and_shrinks() {
+    let before = free_frames();
+    let mut cache = SlabCache::new("block");
+    let mut objects = [None; 100];
+    for (i, object) in objects.iter_mut().enumerate() {
+        *object = cache.alloc([i as u64; 8]);
+        assert!(object.is_some());
+    }
+    let stats = cache.stats();
+    assert!(stats.slabs > 1);
+    assert_eq!(stats.objects_in_use, 100);
+    assert_eq!(free_frames(), before - stats.slabs as u64);
+
+    for (i, object) in objects.iter().enumerate() {
+        let object: NonNull<[u64; 8]> = object.unwrap();
+        unsafe {
+            assert_eq!(*object.as_ref(), [i as u64; 8]);
+            cache.free(object);
+        }
+    }
+    // 空になったスラブは shrink するまで持っている
+    assert_eq!(cache.stats().empty_slabs, stats.slabs);
+    assert_eq!(cache.shrink(), stats.slabs);
+    assert_eq!(cache.stats().slabs, 0);
+    assert_eq!(free_frames(), before);
+}
+
+// 解放したスロットは再利用される
+#[test_case]
+fn reuses_freed_slots() {
+    let mut cache = SlabCache::new("u32");
+    let a = cache.alloc(1u32).unwrap();
+    unsafe { cache.free(a) };
+    let b = cache.alloc(2u32).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(cache.stats().slabs, 1);
+    unsafe { cache.free(b) };
+}
+
+// shrink しなくても、キャッシュを drop すると空のスラブのフレームが返される
+#[test_case]
+fn drop_returns_all_slabs() {
+    let before = free_frames();
+    {
+        let mut cache = SlabCache::new("block");
+        let mut objects = [None; 100];
+        for (i, object) in objects.iter_mut().enumerate() {
+            *object = Some(cache.alloc([i as u64; 8]).expect("out of memory"));
+        }
+        assert!(cache.stats().slabs > 1);
+        assert!(free_frames() < before);
+        for object in objects.iter() {
+            unsafe { cache.free(object.unwrap()) };
+        }
+    }
+    assert_eq!(free_frames(), before);
+}
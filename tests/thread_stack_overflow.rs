@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::{FmtBuffer, QemuExitCode, exit_qemu, serial_println, serial_print, thread};
+
+entry_point!(main);
+
+// スレッドのスタックがあふれたら、ガードページに触ってスタックの名前付きで報告される
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("thread_stack_overflow::guard_page_hit_is_reported...\t");
+
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+    blog_os::gdt::use_guarded_stacks();
+    thread::init();
+
+    thread::spawn("overflow", stack_overflow).join();
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // panic のメッセージをヒープを使わずに受け取る
+    let message = FmtBuffer::format(format_args!("{}", info));
+    if message.as_str().contains("stack overflow in overflow") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use blog_os::thread;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    // スレッドのスタックは共有したページテーブルとフレームアロケータから割り当てる
+    blog_os::test_init_memory(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_waits_for_all_threads() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let handles: alloc::vec::Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn("worker", move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 4);
+}
+
+// yield しないスレッドもタイマ割込みで切り替えられる
+#[test_case]
+fn busy_thread_is_preempted() {
+    let flag = Arc::new(AtomicUsize::new(0));
+    let worker = {
+        let flag = flag.clone();
+        thread::spawn("busy", move || {
+            while flag.load(Ordering::SeqCst) == 0 {
+                core::hint::spin_loop();
+            }
+        })
+    };
+    // busy スレッドが横取りされなければ、ここには戻ってこない
+    thread::yield_now();
+    flag.store(1, Ordering::SeqCst);
+    worker.join();
+}
+
+// 他のスレッドがスタックを割り当てたり返したりしている間にヒープを広げても、割り当ては失敗しない
+#[test_case]
+fn heap_grows_while_stacks_are_allocated() {
+    let allocators: alloc::vec::Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn("allocator", || {
+                for _ in 0..8 {
+                    let buffer = alloc::vec![1u8; 256 * 1024];
+                    assert_eq!(buffer.iter().map(|&b| usize::from(b)).sum::<usize>(), 256 * 1024);
+                }
+            })
+        })
+        .collect();
+    for _ in 0..16 {
+        thread::spawn("short", || {}).join();
+    }
+    for handle in allocators {
+        handle.join();
+    }
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::memory;
+use blog_os::usermode::{self, ExitStatus};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+    usermode::init(memory::physical_memory_offset());
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn run(name: &str) -> ExitStatus {
+    let program = usermode::program(name).expect("unknown program");
+    usermode::run(program).expect("failed to run the program")
+}
+
+#[test_case]
+fn program_exits_with_syscall() {
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn write_rejects_kernel_pointer() {
+    assert_eq!(run("badwrite"), ExitStatus::Exited(blog_os::syscall::ERROR));
+}
+
+#[test_case]
+fn kernel_memory_access_kills_program() {
+    assert_eq!(run("peek"), ExitStatus::Killed);
+    // 強制終了の後も続けて動かせる
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+}
+
+// ユーザのページはプログラムごとのアドレス空間にだけマップされる
+#[test_case]
+fn user_pages_are_not_mapped_in_kernel_space() {
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+    assert!(!usermode::is_user_range(0x0000_0800_0000_0000, 1));
+}
+
+#[test_case]
+fn elf_program_runs() {
+    assert_eq!(run("hello-elf"), ExitStatus::Exited(0));
+}
+
+// ファイルの中でセグメントが詰まっていても、ページごとの権限でマップして動かせる
+#[test_case]
+fn elf_packed_segments() {
+    assert_eq!(run("hello-packed"), ExitStatus::Exited(0));
+}
+
+// 権限の違うセグメントが同じページを共有する ELF は、書き込めて実行もできるページを作らないように断る
+#[test_case]
+fn elf_segments_sharing_a_page_are_rejected() {
+    let result = usermode::run(usermode::Program::Elf(usermode::HELLO_SHARED_ELF));
+    assert!(matches!(result, Err(usermode::UserError::InvalidAddress(0x0000_0800_0000_0000))));
+    // 断った後も続けて動かせる
+    assert_eq!(run("hello-elf"), ExitStatus::Exited(0));
+}
+
+// 終了したプログラムのページとページテーブルはフレームアロケータに返される
+#[test_case]
+fn frames_are_freed_after_exit() {
+    let free_frames = || memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames();
+    let before = free_frames();
+    assert_eq!(run("hello-elf"), ExitStatus::Exited(0));
+    assert_eq!(run("peek"), ExitStatus::Killed);
+    assert_eq!(free_frames(), before);
+}
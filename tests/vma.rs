@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::PageTableFlags;
+use blog_os::memory;
+use blog_os::memory::inspect;
+use blog_os::memory::mapping;
+use blog_os::memory::vma::{self, Purpose, VmaError};
+
+entry_point!(main);
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * 1024;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> u64 {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+// 範囲は重ならず、指定したアラインメントにそろっていて、find で用途と名前が引ける
+#[test_case]
+fn reserve_non_overlapping() {
+    let a = vma::reserve(Purpose::Process, "a", 12 * KIB, 4 * KIB, flags()).unwrap();
+    let b = vma::reserve(Purpose::Process, "b", 1, 2 * MIB, flags()).unwrap();
+    assert_eq!(b.size, 4 * KIB);
+    assert!(b.start.is_aligned(2 * MIB));
+    assert!(a.end() <= b.start || b.end() <= a.start);
+
+    let found = vma::find(a.start + 8 * KIB).unwrap();
+    assert_eq!(found.purpose, Purpose::Process);
+    assert_eq!(found.name, "a");
+    assert!(vma::find(a.end()).is_none_or(|area| area.name != "a"));
+
+    vma::release(a.start).unwrap();
+    vma::release(b.start).unwrap();
+    assert!(vma::find(a.start).is_none());
+}
+
+// 返した範囲は次の reserve で使われる
+#[test_case]
+fn release_makes_room() {
+    let a = vma::reserve(Purpose::Process, "a", 4 * KIB, 4 * KIB, flags()).unwrap();
+    vma::release(a.start).unwrap();
+    let b = vma::reserve(Purpose::Process, "b", 4 * KIB, 4 * KIB, flags()).unwrap();
+    assert_eq!(a.start, b.start);
+    vma::release(b.start).unwrap();
+}
+
+// マップした範囲を返すと、ページのマップが外れてフレームが戻る
+#[test_case]
+fn release_unmaps_and_frees_frames() {
+    let area = vma::reserve(Purpose::Process, "process", 64 * KIB, 4 * KIB, flags()).unwrap();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        mapping::map_anonymous(
+            mapper.as_mut().unwrap(), area.start, area.size, area.flags,
+            frame_allocator.as_mut().unwrap(),
+        ).expect("map_anonymous failed");
+    }
+    let ptr = (area.end() - 8u64).as_mut_ptr::<u64>();
+    unsafe { ptr.write_volatile(0x1234) };
+    let mapped = free_frames();
+
+    vma::release(area.start).unwrap();
+    // ページテーブルのフレームは残るので、戻るのはページの分だけ
+    assert_eq!(free_frames(), mapped + area.size / 4096);
+    assert!(inspect::translate(area.start).is_err());
+    assert!(vma::find(area.start).is_none());
+}
+
+// MMIO の範囲を返してもフレームはフレームアロケータに戻さない
+#[test_case]
+fn release_mmio_keeps_frames() {
+    let area = vma::reserve(Purpose::Mmio, "vga", 4 * KIB, 4 * KIB, flags()).unwrap();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        unsafe {
+            mapping::map_range(
+                mapper.as_mut().unwrap(), area.start, PhysAddr::new(0xb8000), area.size,
+                area.flags, frame_allocator.as_mut().unwrap(),
+            )
+        }.expect("map_range failed");
+    }
+    let mapped = free_frames();
+
+    vma::release(area.start).unwrap();
+    assert_eq!(free_frames(), mapped);
+    assert!(inspect::translate(area.start).is_err());
+}
+
+#[test_case]
+fn release_unknown_area() {
+    let area = vma::reserve(Purpose::Process, "a", 8 * KIB, 4 * KIB, flags()).unwrap();
+    // 範囲の途中のアドレスでは返せない
+    assert_eq!(vma::release(area.start + 4 * KIB).unwrap_err(), VmaError::NotFound);
+    vma::release(area.start).unwrap();
+    assert_eq!(vma::release(area.start).unwrap_err(), VmaError::NotFound);
+}
+
+// 使い切るとエラーになる
+#[test_case]
+fn reserve_out_of_space() {
+    let result = vma::reserve(Purpose::Process, "huge", u64::MAX / 2, 4 * KIB, flags());
+    assert_eq!(result.unwrap_err(), VmaError::OutOfSpace);
+}
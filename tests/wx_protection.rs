@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+use blog_os::memory::inspect;
+use blog_os::memory::protection;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    blog_os::test_init_memory(boot_info);
+    protection::protect_kernel(&boot_info.memory_map)
+        .expect("failed to protect the kernel mappings");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+static READ_ONLY: [u8; 16] = [1; 16];
+static mut WRITABLE: [u8; 16] = [0; 16];
+
+fn flags(addr: u64) -> PageTableFlags {
+    inspect::translate(VirtAddr::new(addr)).expect("address is not mapped").flags
+}
+
+fn is_executable(flags: PageTableFlags) -> bool {
+    !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+#[test_case]
+fn nx_and_write_protect_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+}
+
+#[test_case]
+fn no_writable_executable_pages() {
+    assert!(protection::find_writable_executable().is_none());
+}
+
+#[inline(never)]
+fn code() {}
+
+// .text は読み出しと実行だけ
+#[test_case]
+fn text_is_read_only_and_executable() {
+    let flags = flags(code as *const () as u64);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(is_executable(flags));
+}
+
+// .rodata は読み出しだけ
+#[test_case]
+fn rodata_is_read_only() {
+    let flags = flags(READ_ONLY.as_ptr() as u64);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!is_executable(flags));
+}
+
+// .data/.bss とヒープ、スタックは読み書きだけ
+#[test_case]
+fn data_heap_and_stack_are_not_executable() {
+    let local = 0u64;
+    let heap = Box::new(0u64);
+    let addrs = [
+        core::ptr::addr_of!(WRITABLE) as u64,
+        &*heap as *const u64 as u64,
+        &local as *const u64 as u64,
+    ];
+    for &addr in addrs.iter() {
+        let flags = flags(addr);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        assert!(!is_executable(flags));
+    }
+}
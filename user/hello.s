@@ -0,0 +1,48 @@
+# カーネルに埋め込んで動かすユーザプログラム
+# ビルド方法(user ディレクトリで実行):
+#   as --64 -o hello.o hello.s
+#   ld -static -nostdlib -z max-page-size=4096 -z noexecstack -Ttext=0x0000080000000000 -o hello.elf hello.o
+# L4 の 0 番のエントリはカーネルと共有しているので、usermode の USER_CODE_ADDR と同じ場所にリンクする
+# hello_packed.elf はファイルの中でセグメントを詰めたもの(仮想アドレスではページが分かれている):
+#   ld -static -nostdlib -z noseparate-code -z noexecstack -Ttext=0x0000080000000000 -o hello_packed.elf hello.o
+# hello_shared.elf は .text と .data が同じページに入るようにしたもので、ローダが断ることを確かめる:
+#   ld -static -nostdlib -z max-page-size=0x10 -z common-page-size=0x10 -z noseparate-code -z noexecstack -Ttext=0x0000080000000000 -o hello_shared.elf hello.o
+
+.intel_syntax noprefix
+
+.set SYS_EXIT, 0
+.set SYS_WRITE, 1
+
+.text
+.global _start
+_start:
+    # .bss はローダが 0 で埋めているはず
+    cmp qword ptr [rip + counter], 0
+    jne fail
+
+    # .data は書き込めるはず
+    mov byte ptr [rip + message], 'H'
+    inc qword ptr [rip + counter]
+
+    mov eax, SYS_WRITE
+    lea rdi, [rip + message]
+    mov esi, message_end - message
+    syscall
+
+    mov eax, SYS_EXIT
+    xor edi, edi
+    syscall
+
+fail:
+    mov eax, SYS_EXIT
+    mov edi, 1
+    syscall
+
+.data
+message:
+    .ascii "hello from an ELF binary!\n"
+message_end:
+
+.bss
+counter:
+    .quad 0